@@ -0,0 +1,98 @@
+//! Measures how much cpu an idle daemon uses, run it with `cargo bench --bench idle_cpu`.
+//! Set `IDLE_SECONDS` to measure for longer than the default.
+
+use std::{
+    env, fs,
+    net::TcpListener,
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Lets the daemon finish starting up before measuring
+const WARMUP: Duration = Duration::from_secs(5);
+const DEFAULT_IDLE_SECONDS: u64 = 20;
+/// The unit of the cpu times in /proc, fixed for user space on linux
+const USER_HZ: f64 = 100.0;
+
+fn main() {
+    if !cfg!(target_os = "linux") {
+        println!("idle_cpu: only supported on linux");
+        return;
+    }
+
+    let idle = Duration::from_secs(
+        env::var("IDLE_SECONDS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_IDLE_SECONDS),
+    );
+    let binary = env!("CARGO_BIN_EXE_dyn-wol");
+
+    let dir = env::temp_dir().join(format!("dyn-wol-idle-cpu-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let token = Command::new(binary)
+        .arg("gen-token")
+        .output()
+        .unwrap()
+        .stdout;
+    let port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let config_path = dir.join("config.toml");
+    fs::write(
+        &config_path,
+        format!(
+            "host_ip = \"127.0.0.1\"\nport = {port}\ntoken = \"{}\"\n\n[identity]\nkey_file = \"{}\"\n",
+            String::from_utf8_lossy(&token).trim(),
+            dir.join("identity").display()
+        ),
+    )
+    .unwrap();
+
+    let mut daemon = Command::new(binary)
+        .arg("daemon")
+        .arg("--config")
+        .arg(&config_path)
+        .env("RUST_LOG", "warn")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    thread::sleep(WARMUP);
+    let start = (Instant::now(), cpu_seconds(daemon.id()));
+    thread::sleep(idle);
+    let end = (Instant::now(), cpu_seconds(daemon.id()));
+    let exited = daemon.try_wait().ok().flatten();
+
+    let _ = daemon.kill();
+    let _ = daemon.wait();
+    let _ = fs::remove_dir_all(&dir);
+
+    let (None, Some(start_cpu), Some(end_cpu)) = (exited, start.1, end.1) else {
+        println!("idle_cpu: the daemon exited early");
+        return;
+    };
+    let wall = end.0.duration_since(start.0).as_secs_f64();
+    println!(
+        "idle_cpu: {:.2}% of one core over {wall:.0}s",
+        (end_cpu - start_cpu) / wall * 100.0
+    );
+}
+
+/// The user and system cpu time the process used so far
+fn cpu_seconds(pid: u32) -> Option<f64> {
+    let stat = fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+    // the fields after the command name, which may contain spaces, start with the state
+    let fields = stat
+        .rsplit_once(')')?
+        .1
+        .split_whitespace()
+        .collect::<Vec<_>>();
+    let utime = fields.get(11)?.parse::<f64>().ok()?;
+    let stime = fields.get(12)?.parse::<f64>().ok()?;
+    Some((utime + stime) / USER_HZ)
+}
@@ -0,0 +1,478 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    convert::Infallible,
+    error::Error,
+    net::SocketAddr,
+    sync::Arc,
+};
+
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    response::sse::{Event, KeepAlive, Sse},
+    routing::{get, post},
+    Json, Router,
+};
+use chrono::{DateTime, Local};
+use futures::{stream, Stream};
+use kanal::{AsyncSender, OneshotAsyncSender};
+use log::{error, info, warn};
+use mac_address::MacAddress;
+use serde::Serialize;
+use tokio::sync::{broadcast::error::RecvError, RwLock};
+
+use crate::{
+    events::{ClusterEvent, EventBus},
+    local_metrics::LocalOccupation,
+    reload::LiveConfig,
+    topics::{
+        host_info::HostInfo,
+        host_occupation::{HostOccupation, HostOccupationMessage},
+        leader_election::LeaderElection,
+    },
+    wake_state::WakeState,
+};
+
+/// How many decisions are kept for the status api
+const DECISION_HISTORY: usize = 100;
+
+/// Asks the decision loop to wake a host, answers whether the magic packet was sent
+pub struct WakeRequest {
+    pub mac_address: MacAddress,
+    pub reply: OneshotAsyncSender<bool>,
+}
+
+#[derive(Clone)]
+pub struct ApiState {
+    config: LiveConfig,
+    host_info: HostInfo,
+    host_occupation: HostOccupation,
+    local_occupation: LocalOccupation,
+    leader_election: LeaderElection,
+    wake_state: Arc<RwLock<WakeState>>,
+    history: Arc<RwLock<History>>,
+    events: EventBus,
+    wake_sender: AsyncSender<WakeRequest>,
+}
+
+#[derive(Default)]
+struct History {
+    decisions: VecDeque<RecordedEvent>,
+}
+
+#[derive(Serialize, Clone)]
+struct RecordedEvent {
+    at: DateTime<Local>,
+    #[serde(flatten)]
+    event: ClusterEvent,
+}
+
+#[derive(Serialize)]
+struct Peer {
+    peer_id: String,
+    name: String,
+    mac_address: MacAddress,
+    last_seen_seconds_ago: f64,
+    protocol_version: u16,
+    capabilities: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct Occupation {
+    /// None until the first sample was taken
+    local: Option<HostOccupationMessage>,
+    peers: HashMap<String, HostOccupationMessage>,
+}
+
+#[derive(Serialize)]
+struct Leader {
+    peer_id: Option<String>,
+    term: Option<u64>,
+    is_us: bool,
+}
+
+#[derive(Serialize)]
+struct Host {
+    name: String,
+    mac_address: MacAddress,
+    state: HostState,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum HostState {
+    Awake,
+    Pending,
+    Quarantined,
+    Asleep,
+}
+
+impl ApiState {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        config: &LiveConfig,
+        host_info: &HostInfo,
+        host_occupation: &HostOccupation,
+        local_occupation: &LocalOccupation,
+        leader_election: &LeaderElection,
+        wake_state: &Arc<RwLock<WakeState>>,
+        events: &EventBus,
+        wake_sender: &AsyncSender<WakeRequest>,
+    ) -> Self {
+        let history = Arc::new(RwLock::new(History::default()));
+
+        // remember recent decisions
+        tokio::spawn({
+            let history = history.clone();
+            let mut receiver = events.subscribe();
+            async move {
+                loop {
+                    let event = match receiver.recv().await {
+                        Ok(v) => v,
+                        Err(RecvError::Lagged(skipped)) => {
+                            warn!("Decision history lagged behind, skipped {skipped} events");
+                            continue;
+                        }
+                        Err(RecvError::Closed) => return,
+                    };
+                    if !event.is_decision() {
+                        continue;
+                    }
+
+                    let mut history = history.write().await;
+                    if history.decisions.len() >= DECISION_HISTORY {
+                        history.decisions.pop_front();
+                    }
+                    history.decisions.push_back(RecordedEvent {
+                        at: Local::now(),
+                        event,
+                    });
+                }
+            }
+        });
+
+        Self {
+            config: config.clone(),
+            host_info: host_info.clone(),
+            host_occupation: host_occupation.clone(),
+            local_occupation: local_occupation.clone(),
+            leader_election: leader_election.clone(),
+            wake_state: wake_state.clone(),
+            history,
+            events: events.clone(),
+            wake_sender: wake_sender.clone(),
+        }
+    }
+}
+
+pub async fn serve(state: ApiState, address: SocketAddr) -> Result<(), Box<dyn Error>> {
+    let router = Router::new()
+        .route("/peers", get(peers))
+        .route("/occupation", get(occupation))
+        .route("/aggregate", get(aggregate))
+        .route("/leader", get(leader))
+        .route("/hosts", get(hosts))
+        .route("/hosts/:name/wake", post(wake))
+        .route("/decisions", get(decisions))
+        .route("/events", get(event_stream))
+        .with_state(state);
+
+    let listener = tokio::net::TcpListener::bind(address).await?;
+    info!("Api listening on {address}");
+    axum::serve(listener, router).await?;
+    Ok(())
+}
+
+async fn peers(State(state): State<ApiState>) -> Json<Vec<Peer>> {
+    let map = state.host_info.get_map();
+    let map = map.read().await;
+    Json(
+        map.iter()
+            .map(|(peer_id, host)| Peer {
+                peer_id: peer_id.to_string(),
+                name: host.name.clone(),
+                mac_address: host.mac_address,
+                last_seen_seconds_ago: host.last_seen.elapsed().as_secs_f64(),
+                protocol_version: host.protocol_version,
+                capabilities: host.capabilities.clone(),
+            })
+            .collect(),
+    )
+}
+
+async fn occupation(State(state): State<ApiState>) -> Json<Occupation> {
+    let map = state.host_occupation.get_map();
+    let map = map.read().await;
+    Json(Occupation {
+        local: state.local_occupation.borrow().clone(),
+        peers: map
+            .iter()
+            .map(|(peer_id, v)| (peer_id.to_string(), v.occupation.clone()))
+            .collect(),
+    })
+}
+
+async fn aggregate(State(state): State<ApiState>) -> Json<Option<HostOccupationMessage>> {
+    let map = state.host_occupation.get_map();
+    let map = map.read().await;
+    let local = state.local_occupation.borrow().clone();
+    Json(local.map(|v| HostOccupation::calculate_total_occupation(&map, &v)))
+}
+
+async fn leader(State(state): State<ApiState>) -> Json<Leader> {
+    let leader = state.leader_election.leader().await;
+    Json(Leader {
+        peer_id: leader.map(|v| v.0.to_string()),
+        term: leader.map(|v| v.1),
+        is_us: state.leader_election.is_leader().await,
+    })
+}
+
+async fn hosts(State(state): State<ApiState>) -> Json<Vec<Host>> {
+    let map = state.host_info.get_map();
+    let running = map
+        .read()
+        .await
+        .values()
+        .map(|v| v.mac_address)
+        .collect::<Vec<_>>();
+    // the same state the decision loop works with
+    let (pending, quarantined) = {
+        let wake_state = state.wake_state.read().await;
+        (
+            wake_state.pending_mac_addresses(),
+            wake_state.quarantined_mac_addresses(),
+        )
+    };
+    Json(
+        state
+            .config
+            .borrow()
+            .hosts
+            .iter()
+            .map(|host| Host {
+                name: host.name.clone(),
+                mac_address: host.mac_address,
+                state: host_state(&host.mac_address, &running, &pending, &quarantined),
+            })
+            .collect(),
+    )
+}
+
+fn host_state(
+    mac_address: &MacAddress,
+    running: &[MacAddress],
+    pending: &[MacAddress],
+    quarantined: &[MacAddress],
+) -> HostState {
+    if running.contains(mac_address) {
+        HostState::Awake
+    } else if pending.contains(mac_address) {
+        HostState::Pending
+    } else if quarantined.contains(mac_address) {
+        HostState::Quarantined
+    } else {
+        HostState::Asleep
+    }
+}
+
+async fn decisions(State(state): State<ApiState>) -> Json<Vec<RecordedEvent>> {
+    Json(
+        state
+            .history
+            .read()
+            .await
+            .decisions
+            .iter()
+            .cloned()
+            .collect(),
+    )
+}
+
+/// Streams every cluster event as server sent events, meant for alerting
+async fn event_stream(
+    State(state): State<ApiState>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let events = stream::unfold(state.events.subscribe(), |mut receiver| async move {
+        loop {
+            let event = match receiver.recv().await {
+                Ok(v) => v,
+                Err(RecvError::Lagged(skipped)) => {
+                    error!("Event stream lagged behind, skipped {skipped} events");
+                    continue;
+                }
+                Err(RecvError::Closed) => return None,
+            };
+            let data = match serde_json::to_string(&event) {
+                Ok(v) => v,
+                Err(err) => {
+                    error!("Could not serialize event: {err:#?}");
+                    continue;
+                }
+            };
+            return Some((Ok(Event::default().data(data)), receiver));
+        }
+    });
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+async fn wake(
+    State(state): State<ApiState>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+) -> StatusCode {
+    let authorized = headers
+        .get("authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .is_some_and(|v| {
+            let today = Local::now().date_naive();
+            state
+                .config
+                .borrow()
+                .token
+                .valid(today)
+                .any(|key| tokens_match(v, &key.secret))
+        });
+    if !authorized {
+        return StatusCode::UNAUTHORIZED;
+    }
+
+    let mac_address = match state.config.borrow().hosts.iter().find(|v| v.name == name) {
+        Some(v) => v.mac_address,
+        None => return StatusCode::NOT_FOUND,
+    };
+
+    let (reply, response) = kanal::oneshot_async();
+    let request = WakeRequest { mac_address, reply };
+    if let Err(err) = state.wake_sender.send(request).await {
+        error!("Could not forward wake request: {err:#?}");
+        return StatusCode::INTERNAL_SERVER_ERROR;
+    }
+
+    match response.recv().await {
+        Ok(true) => StatusCode::ACCEPTED,
+        _ => StatusCode::BAD_GATEWAY,
+    }
+}
+
+/// Compares in constant time so the token can not be guessed by timing the responses
+fn tokens_match(given: &str, expected: &str) -> bool {
+    given.len() == expected.len()
+        && given
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::http::HeaderValue;
+    use libp2p::PeerId;
+    use tokio::sync::watch;
+
+    use super::*;
+    use crate::config::{AppConfig, TokenKey, Tokens};
+
+    const TOKEN: &str = "an-example-token-which-is-long-enough";
+
+    fn state() -> ApiState {
+        let config = AppConfig {
+            token: Tokens(vec![TokenKey {
+                id: "key".into(),
+                secret: TOKEN.into(),
+                not_after: None,
+            }]),
+            ..AppConfig::default()
+        };
+        let (_, config) = watch::channel(Arc::new(config));
+        let (_, local_occupation) = watch::channel(None);
+        let events = EventBus::new();
+        let host_info = HostInfo::new(&events);
+        let host_occupation = HostOccupation::new(&host_info, &local_occupation);
+        let leader_election = LeaderElection::new(
+            PeerId::random(),
+            &events,
+            Duration::from_secs(1),
+            Duration::from_secs(5),
+        );
+        let wake_state = Arc::new(RwLock::new(WakeState::new(
+            Default::default(),
+            events.clone(),
+        )));
+        let (wake_sender, _) = kanal::bounded_async(1);
+        ApiState::new(
+            &config,
+            &host_info,
+            &host_occupation,
+            &local_occupation,
+            &leader_election,
+            &wake_state,
+            &events,
+            &wake_sender,
+        )
+    }
+
+    fn bearer(token: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        let value = HeaderValue::from_str(&format!("Bearer {token}")).unwrap();
+        headers.insert("authorization", value);
+        headers
+    }
+
+    fn mac(v: u8) -> MacAddress {
+        MacAddress::new([2, 0, 0, 0, 0, v])
+    }
+
+    #[test]
+    fn compares_tokens() {
+        assert!(tokens_match(TOKEN, TOKEN));
+        assert!(!tokens_match(
+            TOKEN,
+            "an-example-token-which-is-long-enougH"
+        ));
+        assert!(!tokens_match(TOKEN, "an-example-token"));
+        assert!(!tokens_match("", TOKEN));
+    }
+
+    #[test]
+    fn derives_the_host_state() {
+        let running = [mac(1)];
+        let pending = [mac(1), mac(2)];
+        let quarantined = [mac(2), mac(3)];
+        let state = |v| host_state(&mac(v), &running, &pending, &quarantined);
+
+        // hosts which showed up count as awake even before the next tick clears them
+        assert_eq!(state(1), HostState::Awake);
+        assert_eq!(state(2), HostState::Pending);
+        assert_eq!(state(3), HostState::Quarantined);
+        assert_eq!(state(4), HostState::Asleep);
+    }
+
+    #[tokio::test]
+    async fn refuses_wakes_without_a_valid_token() {
+        let name = Path("host".to_string());
+        assert_eq!(
+            wake(State(state()), name, HeaderMap::new()).await,
+            StatusCode::UNAUTHORIZED
+        );
+
+        let name = Path("host".to_string());
+        assert_eq!(
+            wake(State(state()), name, bearer("not-the-token")).await,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[tokio::test]
+    async fn does_not_wake_unknown_hosts() {
+        let name = Path("unknown".to_string());
+        assert_eq!(
+            wake(State(state()), name, bearer(TOKEN)).await,
+            StatusCode::NOT_FOUND
+        );
+    }
+}
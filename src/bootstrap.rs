@@ -0,0 +1,162 @@
+use std::time::{Duration, Instant};
+
+use libp2p::{swarm::ConnectionId, Multiaddr, PeerId};
+
+/// Keeps connections to the configured bootstrap peers alive, redialing them with an
+/// exponential backoff whenever a dial fails or the connection is lost
+pub struct BootstrapPeers {
+    peers: Vec<BootstrapPeer>,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+}
+
+struct BootstrapPeer {
+    address: Multiaddr,
+    state: DialState,
+    backoff: Duration,
+}
+
+#[derive(Debug, PartialEq)]
+enum DialState {
+    Waiting { until: Instant },
+    Dialing(ConnectionId),
+    Connected(PeerId),
+}
+
+impl BootstrapPeers {
+    /// All peers are due right away
+    pub fn new(
+        addresses: &[Multiaddr],
+        initial_backoff: Duration,
+        max_backoff: Duration,
+        now: Instant,
+    ) -> Self {
+        Self {
+            peers: addresses
+                .iter()
+                .map(|address| BootstrapPeer {
+                    address: address.clone(),
+                    state: DialState::Waiting { until: now },
+                    backoff: initial_backoff,
+                })
+                .collect(),
+            initial_backoff,
+            max_backoff,
+        }
+    }
+
+    /// The peers which should be dialed now, to be passed to `dialing` or `dial_failed`
+    pub fn due(&self, now: Instant) -> Vec<(usize, Multiaddr)> {
+        self.peers
+            .iter()
+            .enumerate()
+            .filter(|(_, peer)| matches!(peer.state, DialState::Waiting { until } if until <= now))
+            .map(|(index, peer)| (index, peer.address.clone()))
+            .collect()
+    }
+
+    pub fn dialing(&mut self, index: usize, connection_id: ConnectionId) {
+        if let Some(peer) = self.peers.get_mut(index) {
+            peer.state = DialState::Dialing(connection_id);
+        }
+    }
+
+    pub fn dial_failed(&mut self, index: usize, now: Instant) {
+        if let Some(peer) = self.peers.get_mut(index) {
+            Self::back_off(peer, self.max_backoff, now);
+        }
+    }
+
+    /// Returns the address if the connection was one of our dials
+    pub fn connected(&mut self, connection_id: ConnectionId, peer_id: PeerId) -> Option<Multiaddr> {
+        let peer = self
+            .peers
+            .iter_mut()
+            .find(|peer| peer.state == DialState::Dialing(connection_id))?;
+        peer.state = DialState::Connected(peer_id);
+        peer.backoff = self.initial_backoff;
+        Some(peer.address.clone())
+    }
+
+    /// Called when one of our dials failed or led to a peer we do not accept
+    pub fn connection_failed(&mut self, connection_id: ConnectionId, now: Instant) {
+        if let Some(peer) = self
+            .peers
+            .iter_mut()
+            .find(|peer| peer.state == DialState::Dialing(connection_id))
+        {
+            Self::back_off(peer, self.max_backoff, now);
+        }
+    }
+
+    /// Called once the last connection to a peer is closed
+    pub fn disconnected(&mut self, peer_id: &PeerId, now: Instant) {
+        for peer in &mut self.peers {
+            if peer.state == DialState::Connected(*peer_id) {
+                Self::back_off(peer, self.max_backoff, now);
+            }
+        }
+    }
+
+    fn back_off(peer: &mut BootstrapPeer, max_backoff: Duration, now: Instant) {
+        peer.state = DialState::Waiting {
+            until: now + peer.backoff,
+        };
+        peer.backoff = (peer.backoff * 2).min(max_backoff);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INITIAL: Duration = Duration::from_secs(5);
+    const MAX: Duration = Duration::from_secs(20);
+
+    fn peers(now: Instant) -> BootstrapPeers {
+        let address = "/ip4/192.0.2.1/tcp/4801".parse::<Multiaddr>().unwrap();
+        BootstrapPeers::new(&[address], INITIAL, MAX, now)
+    }
+
+    #[test]
+    fn backs_off_exponentially_up_to_the_maximum() {
+        let now = Instant::now();
+        let mut peers = peers(now);
+        let mut at = now;
+
+        for expected in [5, 10, 20, 20] {
+            let due = peers.due(at);
+            assert_eq!(due.len(), 1);
+            let connection_id = ConnectionId::new_unchecked(expected);
+            peers.dialing(due[0].0, connection_id);
+            assert!(peers.due(at).is_empty());
+
+            peers.connection_failed(connection_id, at);
+            assert!(peers
+                .due(at + Duration::from_secs(expected as u64 - 1))
+                .is_empty());
+            at += Duration::from_secs(expected as u64);
+        }
+        assert_eq!(peers.due(at).len(), 1);
+    }
+
+    #[test]
+    fn redials_after_disconnect_with_reset_backoff() {
+        let now = Instant::now();
+        let mut peers = peers(now);
+        let peer_id = PeerId::random();
+
+        peers.dial_failed(0, now);
+        let later = now + INITIAL;
+        let connection_id = ConnectionId::new_unchecked(1);
+        peers.dialing(0, connection_id);
+        assert!(peers.connected(connection_id, peer_id).is_some());
+        assert!(peers
+            .connected(ConnectionId::new_unchecked(2), peer_id)
+            .is_none());
+
+        peers.disconnected(&peer_id, later);
+        assert!(peers.due(later).is_empty());
+        assert_eq!(peers.due(later + INITIAL).len(), 1);
+    }
+}
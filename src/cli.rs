@@ -0,0 +1,50 @@
+use clap::{Parser, Subcommand};
+
+use crate::config::DEFAULT_CONFIG_PATH;
+
+#[derive(Parser)]
+#[command(
+    version,
+    about = "Wakes and suspends hosts depending on the cluster occupation"
+)]
+pub struct Cli {
+    /// Runs the daemon with the default config if omitted
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Joins the cluster and wakes or suspends hosts as needed
+    Daemon {
+        #[arg(long, short, default_value = DEFAULT_CONFIG_PATH)]
+        config: String,
+    },
+    /// Sends a single magic packet to a configured host
+    Wake {
+        /// The name or mac address of the configured host
+        host: String,
+        #[arg(long, short, default_value = DEFAULT_CONFIG_PATH)]
+        config: String,
+    },
+    /// Asks the running daemon what it knows about the cluster
+    Status {
+        #[arg(long, short, default_value = DEFAULT_CONFIG_PATH)]
+        config: String,
+    },
+    /// Validates the config and prints every problem found
+    CheckConfig {
+        #[arg(long, short, default_value = DEFAULT_CONFIG_PATH)]
+        config: String,
+    },
+    /// Prints the peer id of this node, generating its identity if there is none yet
+    PeerId {
+        #[arg(long, short, default_value = DEFAULT_CONFIG_PATH)]
+        config: String,
+    },
+    /// Prints a new random token which can be used in the config
+    GenToken {
+        #[arg(long, short, default_value_t = 48, value_parser = clap::value_parser!(u64).range(32..))]
+        length: u64,
+    },
+}
@@ -0,0 +1,115 @@
+use std::{
+    error::Error,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+use mac_address::MacAddress;
+use rand_core::{OsRng, RngCore};
+
+use crate::{
+    config::{config_warnings, load_config, read_config, validate_config},
+    identity::load_or_generate_keypair,
+    send_activation_action::send_activation_action,
+};
+
+const TOKEN_ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+
+pub async fn wake(host: &str, config_path: &str) -> Result<(), Box<dyn Error>> {
+    let config = read_config(config_path)?;
+
+    let configured_host = match config.hosts.iter().find(|v| v.name == host) {
+        Some(v) => v,
+        None => {
+            let mac_address = host
+                .parse::<MacAddress>()
+                .map_err(|_| format!("There is no configured host named {host}"))?;
+            config
+                .hosts
+                .iter()
+                .find(|v| v.mac_address == mac_address)
+                .ok_or_else(|| format!("There is no configured host with mac address {host}"))?
+        }
+    };
+
+    if !send_activation_action(configured_host).await {
+        return Err(format!("Could not send magic packet to {}", configured_host.name).into());
+    }
+    println!(
+        "Sent magic packet to {} ({})",
+        configured_host.name, configured_host.mac_address
+    );
+    Ok(())
+}
+
+pub async fn status(config_path: &str) -> Result<(), Box<dyn Error>> {
+    let config = read_config(config_path)?;
+    if !config.api.enabled {
+        return Err("The api is not enabled in the config, so the daemon can not be asked".into());
+    }
+
+    // a daemon listening on all interfaces is reachable through loopback
+    let ip = match config.api.bind_address {
+        IpAddr::V4(v) if v.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        IpAddr::V6(v) if v.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        v => v,
+    };
+    let base_url = match ip {
+        IpAddr::V4(v) => format!("http://{v}:{}", config.api.port),
+        IpAddr::V6(v) => format!("http://[{v}]:{}", config.api.port),
+    };
+
+    for (title, path) in [
+        ("Leader", "/leader"),
+        ("Peers", "/peers"),
+        ("Aggregate occupation", "/aggregate"),
+        ("Configured hosts", "/hosts"),
+        ("Recent decisions", "/decisions"),
+    ] {
+        let response = reqwest::get(format!("{base_url}{path}"))
+            .await?
+            .error_for_status()?
+            .json::<serde_json::Value>()
+            .await?;
+        println!("{title}:\n{}\n", serde_json::to_string_pretty(&response)?);
+    }
+    Ok(())
+}
+
+pub fn check_config(config_path: &str) -> Result<(), Box<dyn Error>> {
+    let (config, mut problems) = load_config(config_path)?;
+    problems.extend(validate_config(&config));
+    for warning in config_warnings(&config) {
+        println!("Warning: {warning}");
+    }
+    if problems.is_empty() {
+        println!("The config is valid");
+        return Ok(());
+    }
+
+    for problem in &problems {
+        println!("{problem}");
+    }
+    Err(format!("Found {} problems in the config", problems.len()).into())
+}
+
+pub fn peer_id(config_path: &str) -> Result<(), Box<dyn Error>> {
+    let config = read_config(config_path)?;
+    let keypair = load_or_generate_keypair(&config.identity.key_file)?;
+    println!("{}", keypair.public().to_peer_id());
+    Ok(())
+}
+
+pub fn gen_token(length: u64) {
+    let token = (0..length)
+        .map(|_| {
+            // rejection sampling so every char is equally likely
+            loop {
+                let byte = (OsRng.next_u32() & 0xff) as usize;
+                if byte < 256 - 256 % TOKEN_ALPHABET.len() {
+                    return TOKEN_ALPHABET[byte % TOKEN_ALPHABET.len()] as char;
+                }
+            }
+        })
+        .collect::<String>();
+    println!("{token}");
+}
@@ -1,55 +1,1128 @@
-use std::error::Error;
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt::Display,
+    net::{IpAddr, Ipv4Addr},
+    str::FromStr,
+};
 
-use config::Config;
-use log::info;
+use chrono::{Local, NaiveDate, NaiveTime, Weekday};
+use config::{builder::DefaultState, Config, ConfigBuilder};
+use libp2p::{Multiaddr, PeerId};
+use log::{info, warn};
 use mac_address::MacAddress;
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer};
+use sha2::{Digest, Sha256};
 
-#[derive(Debug, Deserialize, PartialEq, Eq, Clone)]
+#[derive(Debug, Deserialize, PartialEq, Eq, Clone, Default)]
 pub struct ConfiguredHost {
     pub name: String,
     pub mac_address: MacAddress,
+    /// Hosts with a higher priority are woken first
+    #[serde(default)]
+    pub priority: Option<i32>,
+    /// Breaks ties between hosts of the same priority, heavier hosts are woken first
+    #[serde(default)]
+    pub weight: Option<u32>,
+    #[serde(default)]
+    pub cores: Option<u32>,
+    #[serde(default)]
+    pub memory_mb: Option<u64>,
+    /// Free form labels, schedule windows may refer to hosts by tag
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Where and how the magic packet for this host is sent
+    #[serde(default)]
+    pub wol: WolTarget,
+    /// The host name or peer id of the dyn-wol peer which sends the magic packet for us. Without a
+    /// relay, hosts which are not on one of our subnets are woken by any peer which is, and
+    /// packets to the limited broadcast address are sent by every peer on its own segments.
+    #[serde(default)]
+    pub relay: Option<String>,
 }
 
 #[derive(Debug, Deserialize, PartialEq, Eq, Clone)]
+#[serde(default)]
+pub struct WolTarget {
+    /// A broadcast, directed broadcast or unicast address
+    pub address: IpAddr,
+    pub port: u16,
+    /// The network interface the packet is sent from
+    pub interface: Option<String>,
+    /// The local address the packet is sent from
+    pub bind_address: Option<IpAddr>,
+    /// How often the packet is sent
+    pub repeat: u32,
+    pub repeat_interval_millis: u64,
+    pub secure_on_password: Option<SecureOnPassword>,
+}
+
+impl Default for WolTarget {
+    fn default() -> Self {
+        Self {
+            address: IpAddr::V4(Ipv4Addr::BROADCAST),
+            port: 9,
+            interface: None,
+            bind_address: None,
+            repeat: 1,
+            repeat_interval_millis: 100,
+            secure_on_password: None,
+        }
+    }
+}
+
+/// Six bytes appended to the magic packet for network cards which require a password,
+/// written like a mac address
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct SecureOnPassword(pub [u8; 6]);
+
+impl FromStr for SecureOnPassword {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hex = s.replace([':', '-'], "");
+        if hex.len() != 12 || !hex.is_ascii() {
+            return Err(format!("{s} is not a six byte SecureOn password"));
+        }
+
+        let mut bytes = [0u8; 6];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+                .map_err(|_| format!("{s} is not a six byte SecureOn password"))?;
+        }
+        Ok(SecureOnPassword(bytes))
+    }
+}
+
+impl<'de> Deserialize<'de> for SecureOnPassword {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Every field missing from the config file and the environment falls back to `Default`
+#[derive(Debug, Deserialize, PartialEq, Clone)]
+#[serde(default)]
 pub struct AppConfig {
-    pub host_ip: String,
-    pub port: String,
-    pub token: String,
+    /// The address the swarm listens on
+    pub host_ip: IpAddr,
+    pub port: u16,
+    pub token: Tokens,
     pub hosts: Vec<ConfiguredHost>,
     pub occupation_level_percentage: u8,
+    pub wake_rules: WakeRules,
+    pub policy: PolicyConfig,
+    pub decision: DecisionConfig,
+    pub sampler: SamplerConfig,
+    pub wake: WakeConfig,
+    pub scale_down: ScaleDownConfig,
+    pub liveness: LivenessConfig,
+    pub identity: IdentityConfig,
+    pub discovery: DiscoveryConfig,
+    pub election: ElectionConfig,
+    pub peer_scoring: PeerScoringConfig,
+    pub codec: CodecConfig,
+    pub api: ApiConfig,
+    pub metrics: MetricsConfig,
+}
+
+/// The shared secrets of the cluster. The first key signs our messages and messages signed
+/// with any key are accepted, so the keys can be rotated one node at a time.
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct Tokens(pub Vec<TokenKey>);
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct TokenKey {
+    /// Logged for every peer, so it can be seen when all peers moved to a new key
+    pub id: String,
+    pub secret: String,
+    /// The last day messages signed with this key are accepted
+    pub not_after: Option<NaiveDate>,
+}
+
+impl Tokens {
+    /// The key our messages are signed with
+    pub fn signing(&self) -> Option<&TokenKey> {
+        self.0.first()
+    }
+
+    /// The keys which have not expired yet
+    pub fn valid(&self, today: NaiveDate) -> impl Iterator<Item = &TokenKey> {
+        self.0.iter().filter(move |v| !v.is_expired(today))
+    }
+}
+
+impl TokenKey {
+    /// Keys without an id are named after a fingerprint of their secret, which is the same on
+    /// every node
+    fn new(id: Option<String>, secret: String, not_after: Option<NaiveDate>) -> Self {
+        let id = id.unwrap_or_else(|| {
+            let digest = Sha256::digest(secret.as_bytes());
+            digest[..4].iter().map(|v| format!("{v:02x}")).collect()
+        });
+        Self {
+            id,
+            secret,
+            not_after,
+        }
+    }
+
+    pub fn is_expired(&self, today: NaiveDate) -> bool {
+        self.not_after.is_some_and(|v| v < today)
+    }
+}
+
+impl<'de> Deserialize<'de> for Tokens {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            One(String),
+            Many(Vec<RawKey>),
+        }
+
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum RawKey {
+            Secret(String),
+            Key {
+                id: Option<String>,
+                secret: String,
+                not_after: Option<NaiveDate>,
+            },
+        }
+
+        let keys = match Raw::deserialize(deserializer)? {
+            Raw::One(secret) => vec![TokenKey::new(None, secret, None)],
+            Raw::Many(keys) => keys
+                .into_iter()
+                .map(|v| match v {
+                    RawKey::Secret(secret) => TokenKey::new(None, secret, None),
+                    RawKey::Key {
+                        id,
+                        secret,
+                        not_after,
+                    } => TokenKey::new(id, secret, not_after),
+                })
+                .collect(),
+        };
+        Ok(Tokens(keys))
+    }
+}
+
+/// The prometheus metrics endpoint
+#[derive(Debug, Deserialize, PartialEq, Eq, Clone)]
+#[serde(default)]
+pub struct MetricsConfig {
+    pub enabled: bool,
+    pub bind_address: IpAddr,
+    pub port: u16,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            bind_address: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            port: 9464,
+        }
+    }
+}
+
+/// The local http api to query and control the daemon
+#[derive(Debug, Deserialize, PartialEq, Eq, Clone)]
+#[serde(default)]
+pub struct ApiConfig {
+    pub enabled: bool,
+    pub bind_address: IpAddr,
+    pub port: u16,
+}
+
+impl Default for ApiConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            bind_address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: 8081,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, PartialEq, Eq, Clone)]
+#[serde(default)]
+pub struct LivenessConfig {
+    /// After how many missed broadcasts a peer is considered gone
+    pub missed_intervals: u32,
+}
+
+impl Default for LivenessConfig {
+    fn default() -> Self {
+        Self {
+            missed_intervals: 3,
+        }
+    }
+}
+
+/// Who this node is and which other nodes it talks to
+#[derive(Debug, Deserialize, PartialEq, Eq, Clone)]
+#[serde(default)]
+pub struct IdentityConfig {
+    /// Holds the keypair of this node, it is generated on the first start
+    pub key_file: String,
+    /// The only peers which may join the cluster, everyone with the token may if empty
+    pub allowed_peers: Vec<PeerId>,
+}
+
+impl Default for IdentityConfig {
+    fn default() -> Self {
+        Self {
+            key_file: "/config/dyn-wol-identity".to_string(),
+            allowed_peers: Vec::new(),
+        }
+    }
+}
+
+impl IdentityConfig {
+    pub fn is_allowed(&self, peer_id: &PeerId) -> bool {
+        self.allowed_peers.is_empty() || self.allowed_peers.contains(peer_id)
+    }
+}
+
+/// How the nodes of the cluster find each other
+#[derive(Debug, Deserialize, PartialEq, Eq, Clone)]
+#[serde(default)]
+pub struct DiscoveryConfig {
+    /// Finds peers in the same broadcast domain
+    pub mdns: bool,
+    /// Finds peers through the peers we are already connected to
+    pub kademlia: bool,
+    /// Dialed at startup and whenever the connection to them is lost
+    pub bootstrap_peers: Vec<Multiaddr>,
+    pub redial_initial_seconds: u64,
+    /// The backoff between redials doubles up to this
+    pub redial_max_seconds: u64,
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        Self {
+            mdns: true,
+            kademlia: false,
+            bootstrap_peers: Vec::new(),
+            redial_initial_seconds: 5,
+            redial_max_seconds: 300,
+        }
+    }
+}
+
+/// The election of the peer which takes scaling decisions
+#[derive(Debug, Deserialize, PartialEq, Eq, Clone)]
+#[serde(default)]
+pub struct ElectionConfig {
+    /// How often the leader renews its lease
+    pub heartbeat_seconds: u64,
+    /// How long a leader stays in charge without renewing its lease
+    pub lease_seconds: u64,
+}
+
+impl Default for ElectionConfig {
+    fn default() -> Self {
+        Self {
+            heartbeat_seconds: 3,
+            lease_seconds: 10,
+        }
+    }
+}
+
+/// Gossipsub peer scoring, which graylists and disconnects peers sending invalid messages
+#[derive(Debug, Deserialize, PartialEq, Clone)]
+#[serde(default)]
+pub struct PeerScoringConfig {
+    /// The score below which messages of a peer are ignored and the peer gets disconnected
+    pub graylist_threshold: f64,
+    /// How long a disconnected peer is not connected to again
+    pub ban_seconds: u64,
+}
+
+impl Default for PeerScoringConfig {
+    fn default() -> Self {
+        Self {
+            graylist_threshold: -80.0,
+            ban_seconds: 600,
+        }
+    }
+}
+
+/// How our messages are encoded. Peers decode every encoding, so the encoding can be changed
+/// one node at a time.
+#[derive(Debug, Deserialize, PartialEq, Eq, Clone, Default)]
+#[serde(default)]
+pub struct CodecConfig {
+    pub encoding: Encoding,
+    /// Messages which encode to more bytes than this are compressed with zstd, never if unset
+    pub compress_above_bytes: Option<usize>,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Encoding {
+    #[default]
+    Flexbuffers,
+    Cbor,
+    Protobuf,
+}
+
+/// Rules which decide whether the cluster is occupied enough to wake another host. Without
+/// any rules the average cpu percentage is compared to `occupation_level_percentage`.
+#[derive(Debug, Deserialize, PartialEq, Clone, Default)]
+#[serde(default)]
+pub struct WakeRules {
+    pub combine: Combine,
+    pub rules: Vec<WakeRule>,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Combine {
+    /// Wake if any of the rules matches
+    #[default]
+    Any,
+    /// Wake only if all of the rules match
+    All,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Clone)]
+pub struct WakeRule {
+    pub metric: Metric,
+    #[serde(default)]
+    pub aggregate: Aggregate,
+    /// The rule matches once the aggregated metric exceeds this value
+    pub above: f32,
+    #[serde(default)]
+    pub reading: Reading,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Metric {
+    CpuPercentage,
+    MemoryPercentage,
+    SwapPercentage,
+    Load1PerCore,
+    Load5PerCore,
+    Load15PerCore,
+    DiskBytesPerSecond,
+    NetworkBytesPerSecond,
+}
+
+impl Metric {
+    pub const ALL: [Metric; 8] = [
+        Metric::CpuPercentage,
+        Metric::MemoryPercentage,
+        Metric::SwapPercentage,
+        Metric::Load1PerCore,
+        Metric::Load5PerCore,
+        Metric::Load15PerCore,
+        Metric::DiskBytesPerSecond,
+        Metric::NetworkBytesPerSecond,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Metric::CpuPercentage => "cpu_percentage",
+            Metric::MemoryPercentage => "memory_percentage",
+            Metric::SwapPercentage => "swap_percentage",
+            Metric::Load1PerCore => "load1_per_core",
+            Metric::Load5PerCore => "load5_per_core",
+            Metric::Load15PerCore => "load15_per_core",
+            Metric::DiskBytesPerSecond => "disk_bytes_per_second",
+            Metric::NetworkBytesPerSecond => "network_bytes_per_second",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Aggregate {
+    #[default]
+    Average,
+    Max,
+}
+
+/// Which value of each host a rule looks at. Peers which do not report smoothed or peak
+/// values are judged by their latest sample.
+#[derive(Debug, Deserialize, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Reading {
+    /// The latest sample
+    #[default]
+    Current,
+    /// The exponentially weighted moving average of the samples
+    Smoothed,
+    /// The `peak_percentile` of the samples within the window
+    Peak,
+}
+
+/// Selects the scaling policy which decides when hosts are woken or suspended
+#[derive(Debug, Deserialize, PartialEq, Eq, Clone, Default)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum PolicyConfig {
+    /// Wake when the average occupation exceeds `occupation_level_percentage`
+    #[default]
+    ThresholdAverage,
+    /// Wake when any single host exceeds `occupation_level_percentage`
+    MaxOfAny,
+    /// Wake or suspend as many hosts as needed to reach the target occupation
+    TargetUtilisation { target_percentage: u8 },
+    /// Keep hosts awake during fixed time windows
+    Schedule { windows: Vec<ScheduleWindow> },
+}
+
+#[derive(Debug, Deserialize, PartialEq, Eq, Clone)]
+pub struct ScheduleWindow {
+    /// The weekdays this window applies to, all days if empty
+    #[serde(default)]
+    pub days: Vec<Weekday>,
+    pub from: NaiveTime,
+    pub until: NaiveTime,
+    /// Names or tags of the configured hosts which should be awake during this window
+    pub hosts: Vec<String>,
+}
+
+/// How the local occupation is sampled and smoothed
+#[derive(Debug, Deserialize, PartialEq, Clone)]
+#[serde(default)]
+pub struct SamplerConfig {
+    pub interval_millis: u64,
+    /// After how long a change of the occupation is half reflected in the smoothed values
+    pub half_life_seconds: f64,
+    /// How far back the peak values look
+    pub window_seconds: u64,
+    pub peak_percentile: u8,
+}
+
+impl Default for SamplerConfig {
+    fn default() -> Self {
+        Self {
+            interval_millis: 1000,
+            half_life_seconds: 10.0,
+            window_seconds: 60,
+            peak_percentile: 95,
+        }
+    }
+}
+
+/// When the scaling decision is taken. Besides on every tick it is taken whenever a peer
+/// reports a new occupation.
+#[derive(Debug, Deserialize, PartialEq, Eq, Clone)]
+#[serde(default)]
+pub struct DecisionConfig {
+    /// How often the decision is taken without news
+    pub evaluation_interval_seconds: u64,
+}
+
+impl Default for DecisionConfig {
+    fn default() -> Self {
+        Self {
+            evaluation_interval_seconds: 3,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, PartialEq, Eq, Clone)]
+#[serde(default)]
+pub struct WakeConfig {
+    /// How long the occupation must stay above the threshold before a host gets woken
+    pub breach_hold_seconds: u64,
+    /// How long a woken host may take to show up before the wake counts as failed
+    pub boot_timeout_seconds: u64,
+    /// How often a failed wake is repeated before the host counts as unresponsive
+    pub max_retries: u32,
+    /// How long to wait after a failed wake before retrying, doubled with every retry
+    pub retry_backoff_seconds: u64,
+    /// How long an unresponsive host is skipped when choosing hosts to wake
+    pub quarantine_seconds: u64,
+    /// The minimum time between two activations
+    pub cooldown_seconds: u64,
+    /// How many activations are allowed per activation window
+    pub max_activations: usize,
+    pub activation_window_seconds: u64,
+}
+
+impl Default for WakeConfig {
+    fn default() -> Self {
+        Self {
+            breach_hold_seconds: 30,
+            boot_timeout_seconds: 300,
+            max_retries: 2,
+            retry_backoff_seconds: 30,
+            quarantine_seconds: 3600,
+            cooldown_seconds: 60,
+            max_activations: 3,
+            activation_window_seconds: 900,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, PartialEq, Eq, Clone)]
+#[serde(default)]
+pub struct ScaleDownConfig {
+    /// Hosts which do not enable it ignore the shutdown requests of the cluster
+    pub enabled: bool,
+    /// The cluster wide occupation below which idle hosts get shut down. Only hosts which were
+    /// woken by dyn-wol are shut down, except by the schedule policy.
+    pub occupation_level_percentage: u8,
+    /// How long the occupation must stay below the threshold before a host gets shut down
+    pub hold_seconds: u64,
+    /// The command this host runs when the cluster asks it to shut down
+    pub command: String,
+}
+
+impl Default for ScaleDownConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            occupation_level_percentage: 20,
+            hold_seconds: 300,
+            command: "systemctl suspend".to_string(),
+        }
+    }
 }
 
 impl Default for AppConfig {
     fn default() -> Self {
         Self {
-            host_ip: "0.0.0.0".to_string(),
-            port: "8080".to_string(),
-            token: String::default(),
+            host_ip: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            port: 8080,
+            token: Tokens::default(),
             hosts: Vec::new(),
             occupation_level_percentage: 80,
+            wake_rules: WakeRules::default(),
+            policy: PolicyConfig::default(),
+            decision: DecisionConfig::default(),
+            sampler: SamplerConfig::default(),
+            wake: WakeConfig::default(),
+            scale_down: ScaleDownConfig::default(),
+            liveness: LivenessConfig::default(),
+            identity: IdentityConfig::default(),
+            discovery: DiscoveryConfig::default(),
+            election: ElectionConfig::default(),
+            peer_scoring: PeerScoringConfig::default(),
+            codec: CodecConfig::default(),
+            api: ApiConfig::default(),
+            metrics: MetricsConfig::default(),
         }
     }
 }
 
-pub fn read_config() -> Result<AppConfig, Box<dyn Error>> {
-    info!("Reading config...");
-    let settings = Config::builder()
-        .add_source(config::File::with_name("/config/dyn-wol-config"))
-        .add_source(config::Environment::with_prefix("DYN_WOL"))
-        .build()?;
+/// Where the config is read from if no other path is given
+pub const DEFAULT_CONFIG_PATH: &str = "/config/dyn-wol-config";
+
+/// The minimum estimated entropy of the token, a random token of 32 chars has about 150 bits
+const MIN_TOKEN_ENTROPY_BITS: f64 = 96.0;
+
+/// A problem found in the config, with the path of the offending field
+#[derive(Debug, PartialEq, Eq)]
+pub struct Problem {
+    pub path: String,
+    pub message: String,
+}
+
+impl Problem {
+    fn new(path: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            path: path.into(),
+            message: message.into(),
+        }
+    }
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+/// Reads the config from the given file and the environment. Values which can not be parsed
+/// are replaced by their default and returned as problems, so they are reported together with
+/// the problems found by `validate_config`.
+pub fn load_config(path: &str) -> Result<(AppConfig, Vec<Problem>), Box<dyn Error>> {
+    load(
+        Config::builder()
+            .add_source(config::File::with_name(path))
+            .add_source(config::Environment::with_prefix("DYN_WOL")),
+    )
+}
+
+fn load(builder: ConfigBuilder<DefaultState>) -> Result<(AppConfig, Vec<Problem>), Box<dyn Error>> {
+    let settings = builder.build()?;
+
+    let defaults = AppConfig::default();
+    let typed_fields: [(&str, ParseFn, String); 6] = [
+        ("host_ip", parse_ip, defaults.host_ip.to_string()),
+        ("port", parse_port, defaults.port.to_string()),
+        (
+            "api.bind_address",
+            parse_ip,
+            defaults.api.bind_address.to_string(),
+        ),
+        ("api.port", parse_port, defaults.api.port.to_string()),
+        (
+            "metrics.bind_address",
+            parse_ip,
+            defaults.metrics.bind_address.to_string(),
+        ),
+        (
+            "metrics.port",
+            parse_port,
+            defaults.metrics.port.to_string(),
+        ),
+    ];
+
+    let mut problems = Vec::new();
+    let mut overrides = Config::builder().add_source(settings.clone());
+    for (key, parse, default) in typed_fields {
+        // missing fields take their default, other types are left to the deserializer
+        let Ok(value) = settings.get_string(key) else {
+            continue;
+        };
+        if let Err(message) = parse(&value) {
+            problems.push(Problem::new(key, message));
+            overrides = overrides.set_override(key, default)?;
+        }
+    }
+
+    let settings = if problems.is_empty() {
+        settings
+    } else {
+        overrides.build()?
+    };
+    Ok((settings.try_deserialize()?, problems))
+}
+
+/// Checks a raw value before it is deserialized into its typed field
+type ParseFn = fn(&str) -> Result<(), String>;
+
+fn parse_ip(value: &str) -> Result<(), String> {
+    value
+        .parse::<IpAddr>()
+        .map(|_| ())
+        .map_err(|_| format!("{value} is not an ip address!"))
+}
+
+fn parse_port(value: &str) -> Result<(), String> {
+    value
+        .parse::<u16>()
+        .map(|_| ())
+        .map_err(|_| format!("{value} is not a port between 0 and 65535!"))
+}
+
+/// Checks the config and returns every problem found
+pub fn validate_config(conf: &AppConfig) -> Vec<Problem> {
+    let mut problems = Vec::new();
+
+    if conf.token.0.is_empty() {
+        problems.push(Problem::new(
+            "token",
+            "The token seems to be empty. Please make sure to configure a secure token!",
+        ));
+    }
+    let today = Local::now().date_naive();
+    for (i, key) in conf.token.0.iter().enumerate() {
+        let path = format!("token[{i}]");
+        if key.secret.is_empty() {
+            problems.push(Problem::new(
+                path,
+                "The token seems to be empty. Please make sure to configure a secure token!",
+            ));
+        } else if key.secret.len() < 32 {
+            problems.push(Problem::new(
+                path,
+                "The token is too short, it must have at least 32 chars!",
+            ));
+        } else if token_entropy_bits(&key.secret) < MIN_TOKEN_ENTROPY_BITS {
+            problems.push(Problem::new(
+                path,
+                "The token is too predictable, generate one with `dyn-wol gen-token`!",
+            ));
+        }
+        if conf.token.0[..i].iter().any(|v| v.id == key.id) {
+            problems.push(Problem::new(
+                format!("token[{i}].id"),
+                format!("{} is already used by another key!", key.id),
+            ));
+        }
+    }
+    if conf.token.signing().is_some_and(|v| v.is_expired(today)) {
+        problems.push(Problem::new(
+            "token[0].not_after",
+            "Our messages are signed with the first key, which has expired!",
+        ));
+    }
+
+    if conf.port == 0 {
+        problems.push(Problem::new("port", "The port must not be 0!"));
+    }
+    for (path, enabled, address, port) in [
+        (
+            "api",
+            conf.api.enabled,
+            conf.api.bind_address,
+            conf.api.port,
+        ),
+        (
+            "metrics",
+            conf.metrics.enabled,
+            conf.metrics.bind_address,
+            conf.metrics.port,
+        ),
+    ] {
+        if enabled && port == conf.port && addresses_overlap(address, conf.host_ip) {
+            problems.push(Problem::new(
+                format!("{path}.port"),
+                "Collides with the address the swarm listens on!",
+            ));
+        }
+    }
+    if conf.api.enabled
+        && conf.metrics.enabled
+        && conf.api.port == conf.metrics.port
+        && addresses_overlap(conf.api.bind_address, conf.metrics.bind_address)
+    {
+        problems.push(Problem::new(
+            "metrics.port",
+            "Collides with the address of the api!",
+        ));
+    }
+
+    if conf.occupation_level_percentage > 100 {
+        problems.push(Problem::new(
+            "occupation_level_percentage",
+            "The occupation level must not be above 100!",
+        ));
+    }
+
+    if !conf.discovery.mdns && !conf.discovery.kademlia && conf.discovery.bootstrap_peers.is_empty()
+    {
+        problems.push(Problem::new(
+            "discovery",
+            "Discovery is disabled, enable mdns or kademlia or configure bootstrap peers!",
+        ));
+    }
+    if conf.discovery.kademlia && conf.discovery.bootstrap_peers.is_empty() {
+        problems.push(Problem::new(
+            "discovery.bootstrap_peers",
+            "Kademlia needs at least one bootstrap peer to start from!",
+        ));
+    }
+    if conf.discovery.redial_initial_seconds == 0
+        || conf.discovery.redial_max_seconds < conf.discovery.redial_initial_seconds
+    {
+        problems.push(Problem::new(
+            "discovery.redial_initial_seconds",
+            "The initial redial backoff must be at least 1 second and not above the maximum!",
+        ));
+    }
+
+    if conf.identity.key_file.is_empty() {
+        problems.push(Problem::new(
+            "identity.key_file",
+            "The identity key file must be set!",
+        ));
+    }
+
+    for (i, host) in conf.hosts.iter().enumerate() {
+        if host.wol.repeat == 0 {
+            problems.push(Problem::new(
+                format!("hosts[{i}].wol.repeat"),
+                "The magic packet must be sent at least once!",
+            ));
+        }
+        if let Some(first) = conf.hosts[..i].iter().position(|v| v.name == host.name) {
+            problems.push(Problem::new(
+                format!("hosts[{i}].name"),
+                format!("{} is already used by hosts[{first}]!", host.name),
+            ));
+        }
+        if let Some(first) = conf.hosts[..i]
+            .iter()
+            .position(|v| v.mac_address == host.mac_address)
+        {
+            problems.push(Problem::new(
+                format!("hosts[{i}].mac_address"),
+                format!("{} is already used by hosts[{first}]!", host.mac_address),
+            ));
+        }
+    }
+
+    if conf.decision.evaluation_interval_seconds == 0 {
+        problems.push(Problem::new(
+            "decision.evaluation_interval_seconds",
+            "The evaluation interval must be at least 1 second!",
+        ));
+    }
+
+    let min_interval = sysinfo::MINIMUM_CPU_UPDATE_INTERVAL.as_millis() as u64;
+    if conf.sampler.interval_millis < min_interval {
+        problems.push(Problem::new(
+            "sampler.interval_millis",
+            format!("The interval must be at least {min_interval}ms to measure the cpu usage!"),
+        ));
+    }
+    if conf.sampler.half_life_seconds <= 0.0 {
+        problems.push(Problem::new(
+            "sampler.half_life_seconds",
+            "The half life must be positive!",
+        ));
+    }
+    if conf.sampler.window_seconds == 0 {
+        problems.push(Problem::new(
+            "sampler.window_seconds",
+            "The window must be at least 1 second!",
+        ));
+    }
+    if conf.sampler.peak_percentile > 100 {
+        problems.push(Problem::new(
+            "sampler.peak_percentile",
+            "The peak percentile can not exceed 100!",
+        ));
+    }
+
+    if conf.liveness.missed_intervals == 0 {
+        problems.push(Problem::new(
+            "liveness.missed_intervals",
+            "The liveness missed intervals must be at least 1!",
+        ));
+    }
+
+    if conf.peer_scoring.graylist_threshold >= 0.0 {
+        problems.push(Problem::new(
+            "peer_scoring.graylist_threshold",
+            "The graylist threshold must be negative!",
+        ));
+    }
+
+    if conf.election.heartbeat_seconds == 0 {
+        problems.push(Problem::new(
+            "election.heartbeat_seconds",
+            "The election heartbeat must be at least 1 second!",
+        ));
+    }
+    if conf.election.lease_seconds <= conf.election.heartbeat_seconds {
+        problems.push(Problem::new(
+            "election.lease_seconds",
+            "The election lease must be longer than the heartbeat!",
+        ));
+    }
+
+    if let PolicyConfig::TargetUtilisation { target_percentage } = conf.policy {
+        if target_percentage == 0 || target_percentage > 100 {
+            problems.push(Problem::new(
+                "policy.target_percentage",
+                "The target percentage must be between 1 and 100!",
+            ));
+        }
+    }
+
+    if conf.scale_down.occupation_level_percentage > 100 {
+        problems.push(Problem::new(
+            "scale_down.occupation_level_percentage",
+            "The scale down occupation level must not be above 100!",
+        ));
+    } else if conf.scale_down.enabled
+        && conf.scale_down.occupation_level_percentage >= conf.occupation_level_percentage
+    {
+        problems.push(Problem::new(
+            "scale_down.occupation_level_percentage",
+            "The scale down occupation level must be lower than the occupation level!",
+        ));
+    }
+
+    problems
+}
+
+/// Whether listening on both addresses with the same port would fail
+fn addresses_overlap(a: IpAddr, b: IpAddr) -> bool {
+    a == b || a.is_unspecified() || b.is_unspecified()
+}
 
-    let conf: AppConfig = settings.try_deserialize()?;
+/// Estimates the entropy of the token in bits from how often each of its chars occurs
+fn token_entropy_bits(token: &str) -> f64 {
+    let mut counts = HashMap::new();
+    for c in token.chars() {
+        *counts.entry(c).or_insert(0usize) += 1;
+    }
+    let len = token.chars().count() as f64;
+    counts
+        .values()
+        .map(|&count| {
+            let p = count as f64 / len;
+            -p * p.log2()
+        })
+        .sum::<f64>()
+        * len
+}
+
+/// Finds settings which are valid but most likely do not do what was intended
+pub fn config_warnings(conf: &AppConfig) -> Vec<Problem> {
+    let mut warnings = Vec::new();
+
+    let suspending_policy = match conf.policy {
+        PolicyConfig::TargetUtilisation { .. } => Some("target_utilisation"),
+        PolicyConfig::Schedule { .. } => Some("schedule"),
+        PolicyConfig::ThresholdAverage | PolicyConfig::MaxOfAny => None,
+    };
+    if let Some(policy) = suspending_policy.filter(|_| !conf.scale_down.enabled) {
+        warnings.push(Problem::new(
+            "scale_down.enabled",
+            format!(
+                "The {policy} policy suspends hosts, which is skipped unless scale down is enabled"
+            ),
+        ));
+    }
+
+    warnings
+}
+
+pub fn read_config(path: &str) -> Result<AppConfig, Box<dyn Error>> {
+    info!("Reading config...");
+    let (conf, mut problems) = load_config(path)?;
     info!("Successfully read config!");
+    for warning in config_warnings(&conf) {
+        warn!("{warning}");
+    }
+
+    problems.extend(validate_config(&conf));
+    if !problems.is_empty() {
+        return Err(problems
+            .iter()
+            .map(|v| v.to_string())
+            .collect::<Vec<_>>()
+            .join("\n")
+            .into());
+    }
+    Ok(conf)
+}
+
+#[cfg(test)]
+mod tests {
+    use config::FileFormat;
+
+    use super::*;
+
+    fn load_str(toml: &str) -> (AppConfig, Vec<Problem>) {
+        load(Config::builder().add_source(config::File::from_str(toml, FileFormat::Toml))).unwrap()
+    }
+
+    fn paths(problems: &[Problem]) -> Vec<&str> {
+        problems.iter().map(|v| v.path.as_str()).collect()
+    }
 
-    if conf.token == String::default() {
-        return Err(
-            "The token seems to be empty. Please make sure to configure a secure token!".into(),
+    #[test]
+    fn missing_fields_take_their_default() {
+        let (conf, problems) = load_str(r#"token = "only the token""#);
+        assert!(problems.is_empty());
+        assert_eq!(
+            conf,
+            AppConfig {
+                token: Tokens(vec![TokenKey::new(None, "only the token".into(), None)]),
+                ..Default::default()
+            }
         );
     }
 
-    if conf.token.len() < 32 {
-        return Err("The token is too short, it must have at least 32 chars!".into());
+    #[test]
+    fn reports_every_problem_with_its_path() {
+        let (conf, mut problems) = load_str(
+            r#"
+            host_ip = "not-an-ip"
+            port = 70000
+            token = "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa"
+            occupation_level_percentage = 120
+            [[hosts]]
+            name = "box"
+            mac_address = "aa:bb:cc:dd:ee:ff"
+            [[hosts]]
+            name = "box"
+            mac_address = "AA:BB:CC:DD:EE:FF"
+            [api]
+            enabled = true
+            bind_address = "0.0.0.0"
+            port = 8080
+            "#,
+        );
+        problems.extend(validate_config(&conf));
+        assert_eq!(
+            paths(&problems),
+            [
+                "host_ip",
+                "port",
+                "token[0]",
+                "api.port",
+                "occupation_level_percentage",
+                "hosts[1].name",
+                "hosts[1].mac_address",
+            ]
+        );
+    }
+
+    #[test]
+    fn reads_token_lists() {
+        let (conf, problems) = load_str(
+            r#"
+            token = [
+                { id = "new", secret = "3f9c1e7a5b2d8046f1a9c3e5b7d20486" },
+                { id = "old", secret = "0123456789abcdefghijklmnopqrstuv", not_after = "2024-01-31" },
+                "another-token-which-is-long-enough",
+            ]
+            "#,
+        );
+        assert!(problems.is_empty());
+        let ids = conf
+            .token
+            .0
+            .iter()
+            .map(|v| v.id.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, ["new", "old", "cb0cd25b"]);
+
+        let today = NaiveDate::from_ymd_opt(2024, 2, 1).unwrap();
+        let valid = conf
+            .token
+            .valid(today)
+            .map(|v| v.id.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(valid, ["new", "cb0cd25b"]);
+        assert_eq!(conf.token.signing().unwrap().id, "new");
+    }
+
+    #[test]
+    fn warns_about_suspending_policies_without_scale_down() {
+        let (conf, _) = load_str(
+            r#"
+            policy = { kind = "target_utilisation", target_percentage = 60 }
+            "#,
+        );
+        assert_eq!(paths(&config_warnings(&conf)), ["scale_down.enabled"]);
+
+        let (conf, _) = load_str(
+            r#"
+            policy = { kind = "target_utilisation", target_percentage = 60 }
+            scale_down = { enabled = true }
+            "#,
+        );
+        assert!(config_warnings(&conf).is_empty());
+        assert!(config_warnings(&AppConfig::default()).is_empty());
+    }
+
+    #[test]
+    fn estimates_token_entropy() {
+        assert_eq!(token_entropy_bits("aaaaaaaa"), 0.0);
+        assert_eq!(token_entropy_bits("abababab"), 8.0);
+        // a hex encoded random 128 bit token
+        assert!(token_entropy_bits("3f9c1e7a5b2d8046f1a9c3e5b7d20486") >= MIN_TOKEN_ENTROPY_BITS);
+        assert!(token_entropy_bits("passwordpasswordpasswordpassword") < MIN_TOKEN_ENTROPY_BITS);
     }
-    Ok(conf)
 }
@@ -0,0 +1,264 @@
+use std::time::{Duration, Instant};
+
+use libp2p::PeerId;
+
+/// Lease based leader election. The leader announces itself with a heartbeat every interval,
+/// which extends its lease on every peer. Once the lease runs out without a heartbeat, the next
+/// peer to notice claims leadership for a new term. Competing claims for the same term are won
+/// by the lowest peer id.
+pub struct Election {
+    local_peer_id: PeerId,
+    lease: Duration,
+    term: u64,
+    leader: Option<PeerId>,
+    lease_expires: Instant,
+}
+
+impl Election {
+    /// Waits a full lease before claiming leadership, so a peer joining a running cluster
+    /// learns about the current leader first
+    pub fn new(local_peer_id: PeerId, lease: Duration, now: Instant) -> Self {
+        Self {
+            local_peer_id,
+            lease,
+            term: 0,
+            leader: None,
+            lease_expires: now + lease,
+        }
+    }
+
+    /// Called once per heartbeat interval. Returns the term to announce if we are, or just
+    /// became, the leader.
+    pub fn tick(&mut self, now: Instant) -> Option<u64> {
+        if self.leader == Some(self.local_peer_id) {
+            self.lease_expires = now + self.lease;
+            return Some(self.term);
+        }
+        if now < self.lease_expires {
+            return None;
+        }
+
+        self.term += 1;
+        self.leader = Some(self.local_peer_id);
+        self.lease_expires = now + self.lease;
+        Some(self.term)
+    }
+
+    /// Handles a heartbeat of another peer, returns whether the leader changed
+    pub fn handle_heartbeat(&mut self, term: u64, leader: PeerId, now: Instant) -> bool {
+        if leader == self.local_peer_id {
+            return false;
+        }
+
+        let accept = match term.cmp(&self.term) {
+            std::cmp::Ordering::Greater => true,
+            std::cmp::Ordering::Equal => match self.leader {
+                Some(current) => leader <= current,
+                None => true,
+            },
+            std::cmp::Ordering::Less => false,
+        };
+        if !accept {
+            return false;
+        }
+
+        let changed = self.leader != Some(leader);
+        self.term = term;
+        self.leader = Some(leader);
+        self.lease_expires = now + self.lease;
+        changed
+    }
+
+    pub fn is_leader(&self, now: Instant) -> bool {
+        self.leader == Some(self.local_peer_id) && now < self.lease_expires
+    }
+
+    /// The leader whose lease is still valid
+    pub fn leader(&self, now: Instant) -> Option<PeerId> {
+        self.leader.filter(|_| now < self.lease_expires)
+    }
+
+    pub fn term(&self) -> u64 {
+        self.term
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEARTBEAT: Duration = Duration::from_secs(3);
+    const LEASE: Duration = Duration::from_secs(10);
+
+    /// Peers exchanging heartbeats in lockstep, where every peer only hears the peers in the
+    /// same partition
+    struct Network {
+        now: Instant,
+        nodes: Vec<Election>,
+        partition: Vec<usize>,
+        online: Vec<bool>,
+    }
+
+    impl Network {
+        fn new(size: usize) -> Self {
+            let now = Instant::now();
+            Self {
+                now,
+                nodes: (0..size)
+                    .map(|_| Election::new(PeerId::random(), LEASE, now))
+                    .collect(),
+                partition: vec![0; size],
+                online: vec![true; size],
+            }
+        }
+
+        fn step(&mut self) {
+            self.now += HEARTBEAT;
+            let now = self.now;
+            let heartbeats = self
+                .nodes
+                .iter_mut()
+                .enumerate()
+                .filter(|(i, _)| self.online[*i])
+                .filter_map(|(i, node)| node.tick(now).map(|term| (i, term, node.local_peer_id)))
+                .collect::<Vec<_>>();
+
+            for (from, term, leader) in heartbeats {
+                for (to, node) in self.nodes.iter_mut().enumerate() {
+                    if to != from && self.online[to] && self.partition[to] == self.partition[from] {
+                        node.handle_heartbeat(term, leader, now);
+                    }
+                }
+            }
+        }
+
+        fn run(&mut self, steps: usize) {
+            for _ in 0..steps {
+                self.step();
+            }
+        }
+
+        fn leaders(&self) -> Vec<usize> {
+            (0..self.nodes.len())
+                .filter(|i| self.online[*i] && self.nodes[*i].is_leader(self.now))
+                .collect()
+        }
+
+        /// Asserts every online peer of the partition agrees on a single leader and returns it
+        fn agreed_leader(&self, partition: usize) -> usize {
+            let members = (0..self.nodes.len())
+                .filter(|i| self.online[*i] && self.partition[*i] == partition)
+                .collect::<Vec<_>>();
+            let leaders = members
+                .iter()
+                .copied()
+                .filter(|i| self.nodes[*i].is_leader(self.now))
+                .collect::<Vec<_>>();
+            assert_eq!(leaders.len(), 1, "expected exactly one leader");
+
+            let leader_id = self.nodes[leaders[0]].local_peer_id;
+            for i in members {
+                assert_eq!(self.nodes[i].leader(self.now), Some(leader_id));
+            }
+            leaders[0]
+        }
+    }
+
+    #[test]
+    fn elects_single_leader() {
+        let mut network = Network::new(5);
+        assert!(network.leaders().is_empty());
+
+        network.run(5);
+        network.agreed_leader(0);
+    }
+
+    #[test]
+    fn leader_keeps_lease_while_alive() {
+        let mut network = Network::new(5);
+        network.run(5);
+        let leader = network.agreed_leader(0);
+        let term = network.nodes[leader].term();
+
+        network.run(50);
+        assert_eq!(network.agreed_leader(0), leader);
+        assert_eq!(network.nodes[leader].term(), term);
+    }
+
+    #[test]
+    fn takes_over_when_leader_dies() {
+        let mut network = Network::new(5);
+        network.run(5);
+        let leader = network.agreed_leader(0);
+        let term = network.nodes[leader].term();
+
+        network.online[leader] = false;
+        network.run(5);
+        let new_leader = network.agreed_leader(0);
+        assert_ne!(new_leader, leader);
+        assert!(network.nodes[new_leader].term() > term);
+    }
+
+    #[test]
+    fn followers_wait_for_lease_to_expire() {
+        let mut network = Network::new(3);
+        network.run(5);
+        let leader = network.agreed_leader(0);
+
+        network.online[leader] = false;
+        // until the lease runs out the others still consider the dead peer the leader
+        network.step();
+        assert!(network.leaders().is_empty());
+    }
+
+    #[test]
+    fn converges_after_partition_heals() {
+        let mut network = Network::new(6);
+        network.run(5);
+        let leader = network.agreed_leader(0);
+
+        for i in 0..6 {
+            network.partition[i] = if i == leader { 0 } else { 1 };
+        }
+        network.run(5);
+        assert_eq!(network.agreed_leader(0), leader);
+        let other_leader = network.agreed_leader(1);
+
+        network.partition = vec![0; 6];
+        network.run(2);
+        // the side which held an election has the higher term and wins
+        assert_eq!(network.agreed_leader(0), other_leader);
+    }
+
+    #[test]
+    fn lowest_peer_id_wins_competing_claims() {
+        let now = Instant::now();
+        let mut a = Election::new(PeerId::random(), LEASE, now);
+        let mut b = Election::new(PeerId::random(), LEASE, now);
+
+        let later = now + LEASE;
+        let term_a = a.tick(later).unwrap();
+        let term_b = b.tick(later).unwrap();
+        assert_eq!(term_a, term_b);
+
+        a.handle_heartbeat(term_b, b.local_peer_id, later);
+        b.handle_heartbeat(term_a, a.local_peer_id, later);
+
+        let lowest = a.local_peer_id.min(b.local_peer_id);
+        assert_eq!(a.leader(later), Some(lowest));
+        assert_eq!(b.leader(later), Some(lowest));
+        assert_eq!(a.is_leader(later) as u8 + b.is_leader(later) as u8, 1);
+    }
+
+    #[test]
+    fn ignores_stale_terms() {
+        let now = Instant::now();
+        let mut node = Election::new(PeerId::random(), LEASE, now);
+        let current = PeerId::random();
+        let stale = PeerId::random();
+
+        assert!(node.handle_heartbeat(5, current, now));
+        assert!(!node.handle_heartbeat(4, stale, now));
+        assert_eq!(node.leader(now), Some(current));
+    }
+}
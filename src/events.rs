@@ -0,0 +1,182 @@
+use std::fmt::Display;
+
+use libp2p::PeerId;
+use log::info;
+use mac_address::MacAddress;
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+/// State transitions of the cluster which are interesting to anyone watching the daemon
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ClusterEvent {
+    PeerDiscovered {
+        peer_id: PeerId,
+        name: String,
+        mac_address: MacAddress,
+    },
+    PeerEvicted {
+        peer_id: PeerId,
+        reason: EvictionReason,
+    },
+    /// A magic packet was sent to the host, either by a scaling decision or manually
+    HostWoken {
+        mac_address: MacAddress,
+        name: String,
+        manual: bool,
+    },
+    /// A peer on another subnet sent the magic packet for us
+    WakeRelayed {
+        mac_address: MacAddress,
+        peer_id: PeerId,
+        name: String,
+    },
+    /// A woken host showed up in the cluster
+    HostBooted {
+        mac_address: MacAddress,
+        attempt: u32,
+    },
+    /// A woken host did not show up within the boot timeout
+    WakeFailed {
+        mac_address: MacAddress,
+        attempt: u32,
+    },
+    /// The magic packet was sent again after a failed wake
+    WakeRetried {
+        mac_address: MacAddress,
+        name: String,
+        attempt: u32,
+    },
+    /// A host did not come up after all retries and is skipped for a while
+    HostUnresponsive {
+        mac_address: MacAddress,
+        attempts: u32,
+        quarantine_seconds: u64,
+    },
+    SuspendRequested {
+        peer_id: PeerId,
+        name: String,
+    },
+    /// Another peer, or we, took over scaling decisions
+    LeaderChanged {
+        peer_id: PeerId,
+        term: u64,
+    },
+}
+
+impl ClusterEvent {
+    /// Whether this event is the result of or the outcome of a scaling decision
+    pub fn is_decision(&self) -> bool {
+        matches!(
+            self,
+            ClusterEvent::HostWoken { .. }
+                | ClusterEvent::WakeRelayed { .. }
+                | ClusterEvent::HostBooted { .. }
+                | ClusterEvent::WakeFailed { .. }
+                | ClusterEvent::WakeRetried { .. }
+                | ClusterEvent::HostUnresponsive { .. }
+                | ClusterEvent::SuspendRequested { .. }
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EvictionReason {
+    /// The peer missed too many broadcasts
+    Stale,
+    ConnectionClosed,
+    MdnsExpired,
+}
+
+impl Display for ClusterEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClusterEvent::PeerDiscovered {
+                peer_id,
+                name,
+                mac_address,
+            } => write!(
+                f,
+                "event=peer_discovered peer_id={peer_id} name={name:?} mac_address={mac_address}"
+            ),
+            ClusterEvent::PeerEvicted { peer_id, reason } => {
+                write!(f, "event=peer_evicted peer_id={peer_id} reason={reason:?}")
+            }
+            ClusterEvent::HostWoken {
+                mac_address,
+                name,
+                manual,
+            } => write!(
+                f,
+                "event=host_woken mac_address={mac_address} name={name:?} manual={manual}"
+            ),
+            ClusterEvent::WakeRelayed {
+                mac_address,
+                peer_id,
+                name,
+            } => write!(
+                f,
+                "event=wake_relayed mac_address={mac_address} peer_id={peer_id} name={name:?}"
+            ),
+            ClusterEvent::HostBooted {
+                mac_address,
+                attempt,
+            } => write!(
+                f,
+                "event=host_booted mac_address={mac_address} attempt={attempt}"
+            ),
+            ClusterEvent::WakeFailed {
+                mac_address,
+                attempt,
+            } => write!(
+                f,
+                "event=wake_failed mac_address={mac_address} attempt={attempt}"
+            ),
+            ClusterEvent::WakeRetried {
+                mac_address,
+                name,
+                attempt,
+            } => write!(
+                f,
+                "event=wake_retried mac_address={mac_address} name={name:?} attempt={attempt}"
+            ),
+            ClusterEvent::HostUnresponsive {
+                mac_address,
+                attempts,
+                quarantine_seconds,
+            } => write!(
+                f,
+                "event=host_unresponsive mac_address={mac_address} attempts={attempts} quarantine_seconds={quarantine_seconds}"
+            ),
+            ClusterEvent::SuspendRequested { peer_id, name } => {
+                write!(f, "event=suspend_requested peer_id={peer_id} name={name:?}")
+            }
+            ClusterEvent::LeaderChanged { peer_id, term } => {
+                write!(f, "event=leader_changed peer_id={peer_id} term={term}")
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<ClusterEvent>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(256);
+        Self { sender }
+    }
+
+    pub fn emit(&self, event: ClusterEvent) {
+        info!("{event}");
+        // having nobody listening is fine
+        let _ = self.sender.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<ClusterEvent> {
+        self.sender.subscribe()
+    }
+}
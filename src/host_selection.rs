@@ -0,0 +1,113 @@
+use std::cmp::Reverse;
+
+use mac_address::MacAddress;
+
+use crate::config::ConfiguredHost;
+
+/// Orders the configured hosts in which they should be woken, leaving out the excluded ones.
+/// Hosts with a higher priority come first, ties are broken by weight, then by capacity and
+/// finally by name so the order is always the same.
+pub fn order_wake_candidates<'a>(
+    hosts: &'a [ConfiguredHost],
+    excluded_mac_addresses: &[MacAddress],
+) -> Vec<&'a ConfiguredHost> {
+    let mut candidates = hosts
+        .iter()
+        .filter(|host| !excluded_mac_addresses.contains(&host.mac_address))
+        .collect::<Vec<_>>();
+
+    candidates.sort_by_key(|host| {
+        (
+            Reverse(host.priority.unwrap_or_default()),
+            Reverse(host.weight.unwrap_or_default()),
+            Reverse(host.cores.unwrap_or_default()),
+            Reverse(host.memory_mb.unwrap_or_default()),
+            &host.name,
+        )
+    });
+    candidates
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn host(name: &str, last_byte: u8) -> ConfiguredHost {
+        ConfiguredHost {
+            name: name.to_string(),
+            mac_address: MacAddress::new([0, 0, 0, 0, 0, last_byte]),
+            ..Default::default()
+        }
+    }
+
+    fn names(hosts: Vec<&ConfiguredHost>) -> Vec<&str> {
+        hosts.into_iter().map(|v| v.name.as_str()).collect()
+    }
+
+    #[test]
+    fn orders_by_priority_first() {
+        let hosts = vec![
+            ConfiguredHost {
+                priority: Some(1),
+                weight: Some(100),
+                ..host("low", 1)
+            },
+            ConfiguredHost {
+                priority: Some(10),
+                ..host("high", 2)
+            },
+            host("none", 3),
+        ];
+
+        assert_eq!(
+            names(order_wake_candidates(&hosts, &[])),
+            ["high", "low", "none"]
+        );
+    }
+
+    #[test]
+    fn breaks_ties_by_weight_then_capacity_then_name() {
+        let hosts = vec![
+            host("b", 1),
+            ConfiguredHost {
+                cores: Some(4),
+                ..host("small", 2)
+            },
+            ConfiguredHost {
+                cores: Some(16),
+                ..host("big", 3)
+            },
+            ConfiguredHost {
+                weight: Some(5),
+                ..host("heavy", 4)
+            },
+            host("a", 5),
+        ];
+
+        assert_eq!(
+            names(order_wake_candidates(&hosts, &[])),
+            ["heavy", "big", "small", "a", "b"]
+        );
+    }
+
+    #[test]
+    fn excludes_running_hosts() {
+        let hosts = vec![host("a", 1), host("b", 2), host("c", 3)];
+
+        assert_eq!(
+            names(order_wake_candidates(
+                &hosts,
+                &[hosts[0].mac_address, hosts[2].mac_address]
+            )),
+            ["b"]
+        );
+    }
+
+    #[test]
+    fn handles_empty_candidates() {
+        let hosts = vec![host("a", 1)];
+
+        assert!(order_wake_candidates(&[], &[]).is_empty());
+        assert!(order_wake_candidates(&hosts, &[hosts[0].mac_address]).is_empty());
+    }
+}
@@ -0,0 +1,92 @@
+use std::{error::Error, fs, io::Write, path::Path};
+
+use libp2p::identity::Keypair;
+use log::{info, warn};
+
+/// Loads the keypair of this node, or generates one on the first start so the peer id stays the
+/// same across restarts
+pub fn load_or_generate_keypair(path: &str) -> Result<Keypair, Box<dyn Error>> {
+    let path = Path::new(path);
+    if path.exists() {
+        warn_if_readable_by_others(path);
+        let bytes = fs::read(path)?;
+        let keypair = Keypair::from_protobuf_encoding(&bytes)
+            .map_err(|err| format!("Could not decode the key file {}: {err}", path.display()))?;
+        return Ok(keypair);
+    }
+
+    info!("Generating a new identity in {}", path.display());
+    let keypair = Keypair::generate_ed25519();
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = create_private_file(path)?;
+    file.write_all(&keypair.to_protobuf_encoding()?)?;
+    file.sync_all()?;
+    Ok(keypair)
+}
+
+#[cfg(unix)]
+fn create_private_file(path: &Path) -> std::io::Result<fs::File> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+}
+
+#[cfg(not(unix))]
+fn create_private_file(path: &Path) -> std::io::Result<fs::File> {
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+}
+
+#[cfg(unix)]
+fn warn_if_readable_by_others(path: &Path) {
+    use std::os::unix::fs::PermissionsExt;
+
+    if let Ok(metadata) = fs::metadata(path) {
+        if metadata.permissions().mode() & 0o077 != 0 {
+            warn!(
+                "The key file {} is accessible by other users, it should only be readable by its owner",
+                path.display()
+            );
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn warn_if_readable_by_others(_path: &Path) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_identity_across_loads() {
+        let directory =
+            std::env::temp_dir().join(format!("dyn-wol-identity-{}", std::process::id()));
+        let path = directory.join("key");
+        let path = path.to_str().unwrap();
+
+        let generated = load_or_generate_keypair(path).unwrap();
+        let loaded = load_or_generate_keypair(path).unwrap();
+        assert_eq!(
+            generated.public().to_peer_id(),
+            loaded.public().to_peer_id()
+        );
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
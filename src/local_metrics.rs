@@ -0,0 +1,345 @@
+use std::{collections::VecDeque, time::Duration};
+
+use sysinfo::{Networks, ProcessRefreshKind, ProcessesToUpdate, System};
+use tokio::{
+    select,
+    sync::watch,
+    time::{self, Instant, MissedTickBehavior},
+};
+
+use crate::{
+    config::SamplerConfig,
+    reload::LiveConfig,
+    topics::host_occupation::{HostOccupationMessage, LoadAverage},
+};
+
+/// Refreshing every process is expensive, so the disk throughput, which sysinfo only knows per
+/// process, is measured less often than the other values
+const DISK_REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The latest sample of the local host with its smoothed and peak values, None until the
+/// first sample was taken
+pub type LocalOccupation = watch::Receiver<Option<HostOccupationMessage>>;
+
+/// Samples the local host in the background, for the broadcasts and the decision loop alike
+pub fn spawn_sampler(config: &LiveConfig) -> LocalOccupation {
+    let (sender, receiver) = watch::channel(None);
+    let mut config = config.clone();
+
+    tokio::spawn(async move {
+        let mut local_metrics = LocalMetrics::new();
+        let mut smoothing = Smoothing::default();
+        let mut sampler = config.borrow_and_update().sampler.clone();
+        let mut interval = sample_interval(&sampler);
+        loop {
+            select! {
+                _ = interval.tick() => {}
+                Ok(()) = config.changed() => {
+                    let changed = config.borrow_and_update().sampler.clone();
+                    if changed.interval_millis != sampler.interval_millis {
+                        interval = sample_interval(&changed);
+                    }
+                    sampler = changed;
+                    continue;
+                }
+            }
+            let sample = local_metrics.sample();
+            sender.send_replace(Some(smoothing.add(sample, Instant::now(), &sampler)));
+        }
+    });
+
+    receiver
+}
+
+/// The first tick is one interval away, the cpu usage is only meaningful once it was
+/// refreshed twice with at least `MINIMUM_CPU_UPDATE_INTERVAL` in between
+fn sample_interval(config: &SamplerConfig) -> time::Interval {
+    let period =
+        Duration::from_millis(config.interval_millis).max(sysinfo::MINIMUM_CPU_UPDATE_INTERVAL);
+    let mut interval = time::interval_at(Instant::now() + period, period);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    interval
+}
+
+/// Reads the occupation of the local host. Throughput values are rates between two samples,
+/// so an instance should be kept around instead of being recreated.
+pub struct LocalMetrics {
+    system: System,
+    networks: Networks,
+    last_sample: Option<Instant>,
+    /// The disk usage of the processes is counted since their last refresh
+    last_disk_refresh: Instant,
+    /// Bytes read and written per second between the last two refreshes of the processes
+    disk_rates: Option<(u64, u64)>,
+}
+
+impl LocalMetrics {
+    pub fn new() -> Self {
+        let mut system = System::new_all();
+        system.refresh_all();
+        Self {
+            system,
+            networks: Networks::new_with_refreshed_list(),
+            last_sample: None,
+            last_disk_refresh: Instant::now(),
+            disk_rates: None,
+        }
+    }
+
+    pub fn sample(&mut self) -> HostOccupationMessage {
+        self.system.refresh_cpu_usage();
+        self.system.refresh_memory();
+        self.networks.refresh();
+
+        let now = Instant::now();
+        let since_disk_refresh = now.duration_since(self.last_disk_refresh);
+        if since_disk_refresh >= DISK_REFRESH_INTERVAL {
+            self.system.refresh_processes_specifics(
+                ProcessesToUpdate::All,
+                true,
+                ProcessRefreshKind::new().with_disk_usage(),
+            );
+            self.last_disk_refresh = now;
+            let (read, written) = self
+                .system
+                .processes()
+                .values()
+                .map(|v| v.disk_usage())
+                .fold((0, 0), |acc, v| {
+                    (acc.0 + v.read_bytes, acc.1 + v.written_bytes)
+                });
+            let seconds = since_disk_refresh.as_secs_f64();
+            self.disk_rates = Some((
+                (read as f64 / seconds) as u64,
+                (written as f64 / seconds) as u64,
+            ));
+        }
+
+        let elapsed = self
+            .last_sample
+            .replace(now)
+            .map(|v| now.duration_since(v).as_secs_f64());
+        // rates are only meaningful once there is a previous sample to compare to
+        let per_second = |bytes: u64| {
+            elapsed
+                .filter(|v| *v > 0f64)
+                .map(|v| (bytes as f64 / v) as u64)
+        };
+
+        let (network_received, network_transmitted) =
+            self.networks.values().fold((0, 0), |acc, v| {
+                (acc.0 + v.received(), acc.1 + v.transmitted())
+            });
+
+        let load_average = System::load_average();
+        HostOccupationMessage {
+            cpu_percentage: self.system.global_cpu_usage(),
+            cpu_count: Some(self.system.cpus().len() as u32),
+            memory_used: Some(self.system.used_memory()),
+            memory_total: Some(self.system.total_memory()),
+            swap_used: Some(self.system.used_swap()),
+            swap_total: Some(self.system.total_swap()),
+            load_average: Some(LoadAverage {
+                one: load_average.one,
+                five: load_average.five,
+                fifteen: load_average.fifteen,
+            }),
+            disk_read_bytes_per_second: self.disk_rates.map(|v| v.0),
+            disk_written_bytes_per_second: self.disk_rates.map(|v| v.1),
+            network_received_bytes_per_second: per_second(network_received),
+            network_transmitted_bytes_per_second: per_second(network_transmitted),
+            smoothed: None,
+            peak: None,
+        }
+    }
+}
+
+/// Keeps the recent samples to derive the smoothed and peak values from
+#[derive(Default)]
+struct Smoothing {
+    window: VecDeque<(Instant, HostOccupationMessage)>,
+    average: Option<HostOccupationMessage>,
+}
+
+impl Smoothing {
+    /// Adds a sample and returns it along with its smoothed and peak values
+    fn add(
+        &mut self,
+        sample: HostOccupationMessage,
+        now: Instant,
+        config: &SamplerConfig,
+    ) -> HostOccupationMessage {
+        let average = match (&self.average, self.window.back()) {
+            (Some(average), Some((last, _))) => {
+                // the weight of the new sample, so a change is half reflected after a half life
+                let elapsed = now.duration_since(*last).as_secs_f64();
+                let alpha = 1.0 - 0.5f64.powf(elapsed / config.half_life_seconds);
+                combine(&[average, &sample], |v| {
+                    v.iter()
+                        .copied()
+                        .reduce(|average, new| average + alpha * (new - average))
+                        .unwrap_or_default()
+                })
+            }
+            _ => combine(&[&sample], |v| v[0]),
+        };
+        self.average = Some(average.clone());
+
+        self.window.push_back((now, sample.clone()));
+        let window = Duration::from_secs(config.window_seconds);
+        while self
+            .window
+            .front()
+            .is_some_and(|v| now.duration_since(v.0) > window)
+        {
+            self.window.pop_front();
+        }
+        let samples = self.window.iter().map(|v| &v.1).collect::<Vec<_>>();
+        let peak = combine(&samples, |v| percentile(v, config.peak_percentile));
+
+        HostOccupationMessage {
+            smoothed: Some(Box::new(average)),
+            peak: Some(Box::new(peak)),
+            ..sample
+        }
+    }
+}
+
+/// Combines each value over the samples which report it. The totals are taken from the
+/// last sample, they do not change over time.
+fn combine(samples: &[&HostOccupationMessage], f: impl Fn(&[f64]) -> f64) -> HostOccupationMessage {
+    let value = |get: fn(&HostOccupationMessage) -> Option<f64>| {
+        let values = samples.iter().filter_map(|v| get(v)).collect::<Vec<_>>();
+        (!values.is_empty()).then(|| f(&values))
+    };
+    let bytes = |get: fn(&HostOccupationMessage) -> Option<u64>| {
+        let values = samples
+            .iter()
+            .filter_map(|v| get(v))
+            .map(|v| v as f64)
+            .collect::<Vec<_>>();
+        (!values.is_empty()).then(|| f(&values) as u64)
+    };
+    let load_average = |get: fn(&LoadAverage) -> f64| {
+        let values = samples
+            .iter()
+            .filter_map(|v| v.load_average.as_ref().map(get))
+            .collect::<Vec<_>>();
+        (!values.is_empty()).then(|| f(&values))
+    };
+    let last = samples.last().copied();
+
+    HostOccupationMessage {
+        cpu_percentage: value(|v| Some(v.cpu_percentage.into())).unwrap_or_default() as f32,
+        cpu_count: last.and_then(|v| v.cpu_count),
+        memory_used: bytes(|v| v.memory_used),
+        memory_total: last.and_then(|v| v.memory_total),
+        swap_used: bytes(|v| v.swap_used),
+        swap_total: last.and_then(|v| v.swap_total),
+        load_average: match (
+            load_average(|v| v.one),
+            load_average(|v| v.five),
+            load_average(|v| v.fifteen),
+        ) {
+            (Some(one), Some(five), Some(fifteen)) => Some(LoadAverage { one, five, fifteen }),
+            _ => None,
+        },
+        disk_read_bytes_per_second: bytes(|v| v.disk_read_bytes_per_second),
+        disk_written_bytes_per_second: bytes(|v| v.disk_written_bytes_per_second),
+        network_received_bytes_per_second: bytes(|v| v.network_received_bytes_per_second),
+        network_transmitted_bytes_per_second: bytes(|v| v.network_transmitted_bytes_per_second),
+        smoothed: None,
+        peak: None,
+    }
+}
+
+/// The nearest rank percentile of the values
+fn percentile(values: &[f64], percentile: u8) -> f64 {
+    let mut sorted = values.to_vec();
+    sorted.sort_by(f64::total_cmp);
+    let rank = (percentile as f64 / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(cpu_percentage: f32) -> HostOccupationMessage {
+        HostOccupationMessage {
+            cpu_percentage,
+            memory_used: Some(100),
+            memory_total: Some(1000),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn smooths_samples_and_keeps_the_peak() {
+        let config = SamplerConfig {
+            half_life_seconds: 10.0,
+            window_seconds: 60,
+            peak_percentile: 95,
+            ..Default::default()
+        };
+        let mut smoothing = Smoothing::default();
+        let start = Instant::now();
+
+        let first = smoothing.add(sample(0.0), start, &config);
+        assert_eq!(first.smoothed.unwrap().cpu_percentage, 0.0);
+
+        // a jump is half reflected after one half life
+        let jumped = smoothing.add(sample(100.0), start + Duration::from_secs(10), &config);
+        assert_eq!(jumped.cpu_percentage, 100.0);
+        assert_eq!(jumped.peak.unwrap().cpu_percentage, 100.0);
+        let smoothed = jumped.smoothed.unwrap();
+        assert_eq!(smoothed.cpu_percentage, 50.0);
+        assert_eq!(smoothed.memory_used, Some(100));
+        assert_eq!(smoothed.memory_total, Some(1000));
+
+        // the peak is forgotten once it left the window
+        let later = smoothing.add(sample(10.0), start + Duration::from_secs(80), &config);
+        assert_eq!(later.peak.unwrap().cpu_percentage, 10.0);
+    }
+
+    #[test]
+    fn takes_the_nearest_rank_percentile() {
+        let values = (1..=20).map(f64::from).rev().collect::<Vec<_>>();
+        assert_eq!(percentile(&values, 95), 19.0);
+        assert_eq!(percentile(&values, 50), 10.0);
+        assert_eq!(percentile(&values, 100), 20.0);
+        assert_eq!(percentile(&values, 0), 1.0);
+        assert_eq!(percentile(&[42.0], 95), 42.0);
+    }
+
+    #[test]
+    fn peaks_each_value_over_the_window() {
+        let config = SamplerConfig {
+            window_seconds: 60,
+            peak_percentile: 50,
+            ..Default::default()
+        };
+        let mut smoothing = Smoothing::default();
+        let start = Instant::now();
+
+        let mut peak = None;
+        for (second, cpu_percentage, memory_used) in
+            [(0, 10.0, 200), (1, 90.0, 100), (2, 30.0, 400)]
+        {
+            let sample = HostOccupationMessage {
+                memory_used: Some(memory_used),
+                ..sample(cpu_percentage)
+            };
+            peak = smoothing
+                .add(sample, start + Duration::from_secs(second), &config)
+                .peak;
+        }
+
+        // the median of every value on its own, not the values of the median sample
+        let peak = peak.unwrap();
+        assert_eq!(peak.cpu_percentage, 30.0);
+        assert_eq!(peak.memory_used, Some(200));
+        assert_eq!(peak.memory_total, Some(1000));
+        assert!(peak.load_average.is_none());
+    }
+}
@@ -0,0 +1,124 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+// TODO we dont really need the mac_address crate with sysinfo
+use mac_address::MacAddress;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{net::UdpSocket, time};
+
+use crate::config::{SecureOnPassword, WolTarget};
+
+/// Builds the payload of a magic packet: six 0xff bytes, the mac address repeated 16 times and
+/// optionally the SecureOn password
+pub fn build_magic_packet(
+    mac_address: &MacAddress,
+    password: Option<&SecureOnPassword>,
+) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(6 + 16 * 6 + 6);
+    packet.extend_from_slice(&[0xff; 6]);
+    for _ in 0..16 {
+        packet.extend_from_slice(&mac_address.bytes());
+    }
+    if let Some(password) = password {
+        packet.extend_from_slice(&password.0);
+    }
+    packet
+}
+
+/// Sends the magic packet for the mac address as described by the target
+pub async fn send_magic_packet(mac_address: &MacAddress, target: &WolTarget) -> io::Result<()> {
+    let packet = build_magic_packet(mac_address, target.secure_on_password.as_ref());
+    let socket = bind_socket(target)?;
+    let destination = SocketAddr::new(target.address, target.port);
+
+    for i in 0..target.repeat {
+        if i > 0 {
+            time::sleep(Duration::from_millis(target.repeat_interval_millis)).await;
+        }
+        socket.send_to(&packet, destination).await?;
+    }
+    Ok(())
+}
+
+fn bind_socket(target: &WolTarget) -> io::Result<UdpSocket> {
+    let domain = match target.address {
+        IpAddr::V4(_) => Domain::IPV4,
+        IpAddr::V6(_) => Domain::IPV6,
+    };
+    let socket = Socket::new(domain, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_broadcast(true)?;
+    socket.set_nonblocking(true)?;
+
+    if let Some(interface) = &target.interface {
+        bind_device(&socket, interface)?;
+    }
+
+    let bind_address = target.bind_address.unwrap_or(match target.address {
+        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    });
+    socket.bind(&SocketAddr::new(bind_address, 0).into())?;
+
+    UdpSocket::from_std(socket.into())
+}
+
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "fuchsia"))]
+fn bind_device(socket: &Socket, interface: &str) -> io::Result<()> {
+    socket.bind_device(Some(interface.as_bytes()))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "fuchsia")))]
+fn bind_device(_socket: &Socket, _interface: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Binding to an interface is not supported on this platform, use bind_address instead",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAC: [u8; 6] = [0x01, 0x23, 0x45, 0x67, 0x89, 0xab];
+
+    #[test]
+    fn builds_plain_packet() {
+        let packet = build_magic_packet(&MacAddress::new(MAC), None);
+
+        let mut expected = vec![0xff; 6];
+        for _ in 0..16 {
+            expected.extend_from_slice(&MAC);
+        }
+        assert_eq!(packet.len(), 102);
+        assert_eq!(packet, expected);
+    }
+
+    #[test]
+    fn appends_secure_on_password() {
+        let password = SecureOnPassword([0xde, 0xad, 0xbe, 0xef, 0x00, 0x01]);
+        let packet = build_magic_packet(&MacAddress::new(MAC), Some(&password));
+
+        assert_eq!(packet.len(), 108);
+        assert_eq!(&packet[..6], &[0xff; 6]);
+        assert_eq!(&packet[6..12], &MAC);
+        assert_eq!(&packet[96..102], &MAC);
+        assert_eq!(&packet[102..], &[0xde, 0xad, 0xbe, 0xef, 0x00, 0x01]);
+    }
+
+    #[test]
+    fn parses_secure_on_password() {
+        assert_eq!(
+            "de:ad:be:ef:00:01".parse::<SecureOnPassword>(),
+            Ok(SecureOnPassword([0xde, 0xad, 0xbe, 0xef, 0x00, 0x01]))
+        );
+        assert_eq!(
+            "DEADBEEF0001".parse::<SecureOnPassword>(),
+            Ok(SecureOnPassword([0xde, 0xad, 0xbe, 0xef, 0x00, 0x01]))
+        );
+        assert!("de:ad:be:ef:00".parse::<SecureOnPassword>().is_err());
+        assert!("zz:ad:be:ef:00:01".parse::<SecureOnPassword>().is_err());
+    }
+}
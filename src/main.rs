@@ -1,43 +1,125 @@
-use config::read_config;
+use api::{ApiState, WakeRequest};
+use bootstrap::BootstrapPeers;
+use chrono::Local;
+use clap::Parser;
+use cli::{Cli, Command};
+use config::{
+    read_config, Aggregate, AppConfig, ConfiguredHost, Metric, Reading, DEFAULT_CONFIG_PATH,
+};
+use events::{ClusterEvent, EventBus, EvictionReason};
 use futures::StreamExt;
-use libp2p::gossipsub::TopicHash;
-use libp2p::swarm::SwarmEvent;
-use libp2p::{gossipsub, mdns, swarm::NetworkBehaviour};
-use libp2p::{noise, tcp, yamux};
-use log::{error, info};
-use send_activation_action::send_activation_action;
+use identity::load_or_generate_keypair;
+use libp2p::core::transport::ListenerId;
+use libp2p::gossipsub::{MessageAcceptance, TopicHash};
+use libp2p::swarm::{behaviour::toggle::Toggle, dial_opts::DialOpts, SwarmEvent};
+use libp2p::{gossipsub, identify, kad, mdns, swarm::NetworkBehaviour, PeerId, StreamProtocol};
+use libp2p::{multiaddr::Protocol, noise, tcp, yamux, Multiaddr};
+use local_metrics::spawn_sampler;
+use log::{error, info, warn};
+use mac_address::MacAddress;
+use metrics::{HostStateLabels, MetricLabels, METRICS};
+use peer_scoring::Bans;
+use policy::{ClusterSnapshot, ScalingDecision};
+use reload::watch_config;
 use std::collections::hash_map::DefaultHasher;
 use std::error::Error;
 use std::hash::{Hash, Hasher};
-use std::time::Duration;
-use tokio::{io, select};
-use topics::extract_topic_message;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::{
+    io, select,
+    sync::RwLock,
+    time::{self, MissedTickBehavior},
+};
+use topics::codec::Encoder;
+use topics::envelope::Authenticator;
 use topics::host_info::HostInfo;
 use topics::host_occupation::HostOccupation;
+use topics::host_shutdown::HostShutdown;
+use topics::leader_election::LeaderElection;
+use topics::registry::TopicRegistry;
+use topics::wake_relay::WakeRelay;
+use topics::{MessageValidator, ValidatedMessage, BROADCAST_INTERVAL};
+use wake_state::WakeState;
 
+mod api;
+mod bootstrap;
+mod cli;
+mod commands;
 mod config;
+mod election;
+mod events;
+mod host_selection;
+mod identity;
+mod local_metrics;
+mod magic_packet;
+mod metrics;
+mod peer_scoring;
+mod policy;
+mod reload;
 mod send_activation_action;
 mod topics;
+mod wake_state;
 
 #[derive(NetworkBehaviour)]
 pub struct MyBehaviour {
     gossipsub: gossipsub::Behaviour,
-    mdns: mdns::tokio::Behaviour,
+    mdns: Toggle<mdns::tokio::Behaviour>,
+    kademlia: Toggle<kad::Behaviour<kad::store::MemoryStore>>,
+    identify: Toggle<identify::Behaviour>,
+}
+
+const KADEMLIA_PROTOCOL: StreamProtocol = StreamProtocol::new("/dyn-wol/kad/1.0.0");
+const IDENTIFY_PROTOCOL: &str = "/dyn-wol/id/1.0.0";
+/// How often the routing table of the DHT is refreshed
+const KADEMLIA_BOOTSTRAP_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Adds a peer found by any of the discovery methods to the gossipsub peers, unless it is banned
+/// or not allowed
+fn add_discovered_peer(
+    swarm: &mut libp2p::Swarm<MyBehaviour>,
+    bans: &mut Bans,
+    identity: &config::IdentityConfig,
+    peer_id: PeerId,
+    method: &str,
+) {
+    if bans.is_banned(&peer_id, Instant::now()) || !identity.is_allowed(&peer_id) {
+        return;
+    }
+    info!("{method} discovered a new peer: {peer_id}");
+    swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer_id);
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     env_logger::init();
-    info!("Starting dyn-wol");
 
     let _ = tracing_subscriber::fmt()
         .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
         .try_init();
 
-    let config = read_config()?;
+    match Cli::parse().command {
+        None => run_daemon(DEFAULT_CONFIG_PATH).await,
+        Some(Command::Daemon { config }) => run_daemon(&config).await,
+        Some(Command::Wake { host, config }) => commands::wake(&host, &config).await,
+        Some(Command::Status { config }) => commands::status(&config).await,
+        Some(Command::CheckConfig { config }) => commands::check_config(&config),
+        Some(Command::PeerId { config }) => commands::peer_id(&config),
+        Some(Command::GenToken { length }) => {
+            commands::gen_token(length);
+            Ok(())
+        }
+    }
+}
+
+async fn run_daemon(config_path: &str) -> Result<(), Box<dyn Error>> {
+    info!("Starting dyn-wol");
+    let config = read_config(config_path)?;
 
     info!("Building swarm...");
-    let mut swarm = libp2p::SwarmBuilder::with_new_identity()
+    let keypair = load_or_generate_keypair(&config.identity.key_file)?;
+    let mut swarm = libp2p::SwarmBuilder::with_existing_identity(keypair)
         .with_tokio()
         .with_tcp(
             tcp::Config::default(),
@@ -55,130 +137,557 @@ async fn main() -> Result<(), Box<dyn Error>> {
             let gossipsub_config = gossipsub::ConfigBuilder::default()
                 .heartbeat_interval(Duration::from_secs(10))
                 .validation_mode(gossipsub::ValidationMode::Strict)
+                // messages are only forwarded once the message validator accepted them
+                .validate_messages()
                 .message_id_fn(message_id_fn)
                 .build()
-                .map_err(|msg| io::Error::new(io::ErrorKind::Other, msg))?;
+                .map_err(io::Error::other)?;
 
             let gossipsub = gossipsub::Behaviour::new(
                 gossipsub::MessageAuthenticity::Signed(key.clone()),
                 gossipsub_config,
             )?;
 
-            let mdns =
-                mdns::tokio::Behaviour::new(mdns::Config::default(), key.public().to_peer_id())?;
-            Ok(MyBehaviour { gossipsub, mdns })
+            let peer_id = key.public().to_peer_id();
+            let mdns = Toggle::from(
+                config
+                    .discovery
+                    .mdns
+                    .then(|| mdns::tokio::Behaviour::new(mdns::Config::default(), peer_id))
+                    .transpose()?,
+            );
+
+            let kademlia = Toggle::from(config.discovery.kademlia.then(|| {
+                let mut kademlia = kad::Behaviour::with_config(
+                    peer_id,
+                    kad::store::MemoryStore::new(peer_id),
+                    kad::Config::new(KADEMLIA_PROTOCOL),
+                );
+                // every node routes for the others, there are no light clients in a cluster
+                kademlia.set_mode(Some(kad::Mode::Server));
+                kademlia
+            }));
+            // identify tells kademlia which addresses the peers are listening on
+            let identify = Toggle::from(config.discovery.kademlia.then(|| {
+                identify::Behaviour::new(identify::Config::new(
+                    IDENTIFY_PROTOCOL.to_string(),
+                    key.public(),
+                ))
+            }));
+
+            Ok(MyBehaviour {
+                gossipsub,
+                mdns,
+                kademlia,
+                identify,
+            })
         })?
         .with_swarm_config(|c| c.with_idle_connection_timeout(Duration::from_secs(60)))
         .build();
     info!("Built swarm!");
 
-    swarm.listen_on(
-        format!(
-            "/ip4/{host_ip}/udp/{port}/quic-v1",
-            host_ip = config.host_ip,
-            port = config.port
-        )
-        .parse()?,
+    let mut listeners = listen(&mut swarm, config.host_ip, config.port)?;
+    let live_config = watch_config(config_path, config.clone());
+
+    let (incoming_sender, incoming_receiver) = kanal::unbounded_async::<ValidatedMessage>();
+    let (outgoing_sender, outgoing_receiver) = kanal::unbounded_async::<(TopicHash, Vec<u8>)>();
+
+    let authenticator = Authenticator::new(&config.token, *swarm.local_peer_id());
+    let events = EventBus::new();
+    let our_peer_id = *swarm.local_peer_id();
+
+    let mut registry = TopicRegistry::new(
+        &outgoing_sender,
+        MessageValidator::new(authenticator.clone(), &config.identity.allowed_peers),
+        Encoder::new(&config.codec),
+    );
+    let host_info_instance = HostInfo::new(&events);
+    registry.register(&mut swarm.behaviour_mut().gossipsub, &host_info_instance)?;
+    let local_occupation = spawn_sampler(&live_config);
+    let host_occupation_instance = HostOccupation::new(&host_info_instance, &local_occupation);
+    registry.register(
+        &mut swarm.behaviour_mut().gossipsub,
+        &host_occupation_instance,
     )?;
-    swarm.listen_on(
-        format!(
-            "/ip4/{host_ip}/tcp/{port}",
-            host_ip = config.host_ip,
-            port = config.port
-        )
-        .parse()?,
+    let host_shutdown_instance = HostShutdown::new(
+        registry.publisher::<HostShutdown>(),
+        our_peer_id,
+        &live_config,
+    );
+    registry.register(
+        &mut swarm.behaviour_mut().gossipsub,
+        &host_shutdown_instance,
+    )?;
+    let wake_relay_instance = WakeRelay::new(
+        registry.publisher::<WakeRelay>(),
+        our_peer_id,
+        &live_config,
+        &events,
+    );
+    registry.register(&mut swarm.behaviour_mut().gossipsub, &wake_relay_instance)?;
+    let leader_election_instance = LeaderElection::new(
+        our_peer_id,
+        &events,
+        Duration::from_secs(config.election.heartbeat_seconds),
+        Duration::from_secs(config.election.lease_seconds),
+    );
+    registry.register(
+        &mut swarm.behaviour_mut().gossipsub,
+        &leader_election_instance,
     )?;
 
-    let (incoming_sender, incoming_receiver) = kanal::unbounded_async::<gossipsub::Event>();
-    let (outgoing_sender, outgoing_receiver) = kanal::unbounded_async::<(TopicHash, Vec<u8>)>();
+    let validator = registry.validator();
+    let (score_params, score_thresholds) =
+        peer_scoring::score_params(validator.topic_hashes(), &config.peer_scoring);
+    swarm
+        .behaviour_mut()
+        .gossipsub
+        .with_peer_score(score_params, score_thresholds)?;
+
+    // forget about peers which stopped broadcasting
+    tokio::spawn({
+        let host_info = host_info_instance.clone();
+        let host_occupation = host_occupation_instance.clone();
+        let live_config = live_config.clone();
+        async move {
+            let mut interval = time::interval(BROADCAST_INTERVAL);
+            loop {
+                interval.tick().await;
+                let max_age = BROADCAST_INTERVAL * live_config.borrow().liveness.missed_intervals;
+                for peer_id in host_info.evict_stale(max_age).await {
+                    host_occupation.evict_peer(&peer_id).await;
+                }
+                host_occupation.evict_stale(max_age).await;
+            }
+        }
+    });
 
-    let host_info_instance = HostInfo::register(&mut swarm, &config, &outgoing_sender)?;
-    let host_occupation_instance =
-        HostOccupation::register(&mut swarm, &outgoing_sender, &host_info_instance)?;
+    let (wake_sender, wake_receiver) = kanal::bounded_async::<WakeRequest>(16);
+    let wake_state = Arc::new(RwLock::new(WakeState::new(
+        config.wake.clone(),
+        events.clone(),
+    )));
+
+    if config.api.enabled {
+        let state = ApiState::new(
+            &live_config,
+            &host_info_instance,
+            &host_occupation_instance,
+            &local_occupation,
+            &leader_election_instance,
+            &wake_state,
+            &events,
+            &wake_sender,
+        );
+        let address = SocketAddr::new(config.api.bind_address, config.api.port);
+        tokio::spawn(async move {
+            if let Err(err) = api::serve(state, address).await {
+                error!("Api server failed: {err:#?}");
+            }
+        });
+    }
+
+    if config.metrics.enabled {
+        let address = SocketAddr::new(config.metrics.bind_address, config.metrics.port);
+        tokio::spawn(async move {
+            if let Err(err) = metrics::serve(address).await {
+                error!("Metrics server failed: {err:#?}");
+            }
+        });
+    }
 
     tokio::spawn({
         let occupation_map = host_occupation_instance.get_map();
         let info_map = host_info_instance.get_map();
-        let occupation_level_percentage_threshold = config.occupation_level_percentage;
-        let our_peer_id = swarm.local_peer_id().clone();
-        let config = config.clone();
+        let mut occupation_updates = host_occupation_instance.updates();
+        let mut info_updates = host_info_instance.updates();
+        let host_shutdown = host_shutdown_instance.clone();
+        let wake_relay = wake_relay_instance.clone();
+        let leader_election = leader_election_instance.clone();
+        let local_occupation = local_occupation.clone();
+        let wake_state = wake_state.clone();
+        let events = events.clone();
+        let mut live_config = live_config.clone();
         async move {
+            let mut config = live_config.borrow_and_update().clone();
+            let mut policy = policy::from_config(&config);
+            // since when the policy has been asking to shut hosts down
+            let mut suspend_since: Option<Instant> = None;
+            let mut interval = evaluation_interval(&config);
             loop {
-                let total = HostOccupation::calculate_total_occupation(&occupation_map)
-                    .await
-                    .cpu_percentage;
-
-                let occupation_map_lock = occupation_map.read().await;
-                let info_map_lock = info_map.read().await;
-                if total > occupation_level_percentage_threshold as f32 {
-                    info!("Occupation level is too high: {total}");
-                    let lowest_other_peer_id =
-                        occupation_map_lock.iter().map(|v| v.0.to_string()).min();
-
-                    match lowest_other_peer_id {
-                        Some(lowest) => {
-                            if lowest > our_peer_id.to_string() {
-                                continue;
-                            }
+                if live_config.has_changed().unwrap_or_default() {
+                    let previous = config.clone();
+                    config = live_config.borrow_and_update().clone();
+                    policy = policy::from_config(&config);
+                    wake_state.write().await.set_config(config.wake.clone());
+                    if config.decision != previous.decision {
+                        interval = evaluation_interval(&config);
+                    }
+                }
+                select! {
+                    _ = interval.tick() => {}
+                    // the decision is taken again as soon as a peer reports something new
+                    Ok(()) = occupation_updates.changed() => {}
+                    Ok(()) = info_updates.changed() => {}
+                    request = wake_receiver.recv() => {
+                        let Ok(request) = request else { continue };
+                        let Some(host) = configured_host(&config, &request.mac_address) else {
+                            continue;
+                        };
+                        let sent = wake_relay.wake(host).await;
+                        if sent {
+                            wake_state
+                                .write()
+                                .await
+                                .record_manual_activation(host.mac_address, Instant::now());
+                            events.emit(ClusterEvent::HostWoken {
+                                mac_address: host.mac_address,
+                                name: host.name.clone(),
+                                manual: true,
+                            });
                         }
-                        None => {}
+                        let _ = request.reply.send(sent).await;
+                        continue;
                     }
+                }
+                let Some(local) = local_occupation.borrow().clone() else {
+                    continue;
+                };
 
-                    send_activation_action(
-                        info_map_lock
+                let we_decide = leader_election.is_leader().await;
+                METRICS.leader.set(we_decide as i64);
+
+                let now = Instant::now();
+                let running_mac_addresses = info_map
+                    .read()
+                    .await
+                    .iter()
+                    .map(|v| v.1.mac_address)
+                    .collect::<Vec<_>>();
+                let retries = wake_state
+                    .write()
+                    .await
+                    .update_pending(&running_mac_addresses, now);
+                for mac_address in retries {
+                    let Some(host) = configured_host(&config, &mac_address) else {
+                        continue;
+                    };
+                    let attempt = {
+                        let mut wake_state = wake_state.write().await;
+                        let attempt = wake_state.next_attempt(&mac_address).unwrap_or_default();
+                        // the attempt counts even if the packet could not be sent, so a broken
+                        // target ends up in quarantine instead of being retried every tick
+                        wake_state.record_retry(mac_address, now);
+                        attempt
+                    };
+                    if wake_relay.wake(host).await {
+                        METRICS.wake_retries.inc();
+                        events.emit(ClusterEvent::WakeRetried {
+                            mac_address,
+                            name: host.name.clone(),
+                            attempt,
+                        });
+                    }
+                }
+
+                let (pending_mac_addresses, quarantined_mac_addresses) = {
+                    let wake_state = wake_state.read().await;
+                    (
+                        wake_state.pending_mac_addresses(),
+                        wake_state.quarantined_mac_addresses(),
+                    )
+                };
+                let woken_mac_addresses = leader_election.woken_mac_addresses().await;
+                // the maps are only locked while deciding, the handlers must not wait for the
+                // packets and requests we send
+                let (decision, total, suspended) = {
+                    let occupation_map_lock = occupation_map.read().await;
+                    let info_map_lock = info_map.read().await;
+                    let snapshot = ClusterSnapshot {
+                        host_info: &info_map_lock,
+                        host_occupation: &occupation_map_lock,
+                        local: &local,
+                        configured_hosts: &config.hosts,
+                        pending_mac_addresses: &pending_mac_addresses,
+                        quarantined_mac_addresses: &quarantined_mac_addresses,
+                        woken_mac_addresses: &woken_mac_addresses,
+                        now: Local::now(),
+                    };
+                    let total = snapshot.average_cpu_percentage();
+                    update_metrics(&snapshot);
+
+                    let decision = policy.decide(&snapshot);
+                    let suspended = match &decision {
+                        ScalingDecision::Suspend(peer_ids) => peer_ids
                             .iter()
-                            .map(|v| v.1.mac_address)
-                            .collect::<Vec<_>>(),
-                        config
-                            .hosts
+                            .map(|peer_id| {
+                                let info = info_map_lock.get(peer_id);
+                                (*peer_id, info.map(|v| (v.mac_address, v.name.clone())))
+                            })
+                            .collect(),
+                        _ => Vec::new(),
+                    };
+                    (decision, total, suspended)
+                };
+
+                match decision {
+                    ScalingDecision::Wake(mac_addresses) => {
+                        suspend_since = None;
+                        let allowed = wake_state.write().await.allowed_wakes(true, now);
+                        if allowed == 0 || !we_decide {
+                            continue;
+                        }
+
+                        info!("Occupation level is too high: {total}");
+                        for host in mac_addresses
                             .iter()
-                            .map(|v| v.mac_address)
-                            .collect::<Vec<_>>(),
-                    );
+                            .filter_map(|v| configured_host(&config, v))
+                            .take(allowed)
+                        {
+                            if wake_relay.wake(host).await {
+                                wake_state
+                                    .write()
+                                    .await
+                                    .record_activation(host.mac_address, now);
+                                leader_election.record_woken(host.mac_address).await;
+                                events.emit(ClusterEvent::HostWoken {
+                                    mac_address: host.mac_address,
+                                    name: host.name.clone(),
+                                    manual: false,
+                                });
+                            }
+                        }
+                    }
+                    ScalingDecision::Suspend(_) => {
+                        wake_state.write().await.allowed_wakes(false, now);
+                        if !config.scale_down.enabled {
+                            continue;
+                        }
+
+                        let since = *suspend_since.get_or_insert(now);
+                        if now.duration_since(since)
+                            < Duration::from_secs(config.scale_down.hold_seconds)
+                            || !we_decide
+                        {
+                            continue;
+                        }
+
+                        info!("Occupation level is low: {total}");
+                        for (peer_id, info) in suspended {
+                            if let Some((mac_address, _)) = &info {
+                                leader_election.record_suspended(mac_address).await;
+                            }
+                            let name = info.map(|v| v.1).unwrap_or_default();
+                            events.emit(ClusterEvent::SuspendRequested { peer_id, name });
+                            host_shutdown.request_shutdown(&peer_id).await;
+                        }
+                        suspend_since = Some(now);
+                    }
+                    // the breach is still tracked, so a host which becomes available is woken
+                    // right away
+                    ScalingDecision::Hold => {
+                        suspend_since = None;
+                    }
+                    ScalingDecision::Nothing => {
+                        wake_state.write().await.allowed_wakes(false, now);
+                        suspend_since = None;
+                    }
                 }
             }
         }
     });
 
-    tokio::spawn(async move {
-        loop {
-            select! {
-                outgoing = outgoing_receiver.recv() => match outgoing {
-                    Ok(v) => {
-                        if let Err(e) = swarm.behaviour_mut().gossipsub.publish(v.0, v.1) {
-                            error!("Swarm publish error: {e:?}");
+    tokio::spawn({
+        let host_info = host_info_instance.clone();
+        let host_occupation = host_occupation_instance.clone();
+        let authenticator = authenticator.clone();
+        let graylist_threshold = config.peer_scoring.graylist_threshold;
+        let identity = config.identity.clone();
+        let mut bans = Bans::new(Duration::from_secs(config.peer_scoring.ban_seconds));
+        let mut live_config = live_config.clone();
+        let (mut host_ip, mut port) = (config.host_ip, config.port);
+        let mut bootstrap = BootstrapPeers::new(
+            &config.discovery.bootstrap_peers,
+            Duration::from_secs(config.discovery.redial_initial_seconds),
+            Duration::from_secs(config.discovery.redial_max_seconds),
+            Instant::now(),
+        );
+        async move {
+            let mut score_interval = time::interval(BROADCAST_INTERVAL);
+            let mut redial_interval = time::interval(Duration::from_secs(1));
+            let mut kademlia_interval = time::interval(KADEMLIA_BOOTSTRAP_INTERVAL);
+            loop {
+                select! {
+                    Ok(()) = live_config.changed() => {
+                        let (new_host_ip, new_port) = {
+                            let config = live_config.borrow_and_update();
+                            authenticator.set_tokens(&config.token);
+                            (config.host_ip, config.port)
+                        };
+                        if (new_host_ip, new_port) == (host_ip, port) {
+                            continue;
+                        }
+                        info!("Rebinding the listeners to {new_host_ip} port {new_port}");
+                        // the old listeners stay until the new ones are bound, so a failed
+                        // rebind does not leave us unreachable
+                        match listen(&mut swarm, new_host_ip, new_port) {
+                            Ok(v) => {
+                                for listener in std::mem::replace(&mut listeners, v) {
+                                    swarm.remove_listener(listener);
+                                }
+                                (host_ip, port) = (new_host_ip, new_port);
+                            }
+                            Err(err) => error!(
+                                "Could not rebind to {new_host_ip} port {new_port}, keeping the \
+                                 listeners on {host_ip} port {port}: {err:#?}"
+                            ),
                         }
                     },
-                    Err(err) => error!("Could not listen for outgoing: {err:#?}"),
-                },
-                event = swarm.select_next_some() => match event {
-                    SwarmEvent::Behaviour(MyBehaviourEvent::Mdns(mdns::Event::Discovered(list))) => {
-                        for (peer_id, _multiaddr) in list {
-                            info!("mDNS discovered a new peer: {peer_id}");
-                            swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer_id);
+                    _ = redial_interval.tick() => {
+                        let now = Instant::now();
+                        for (index, address) in bootstrap.due(now) {
+                            let opts = DialOpts::unknown_peer_id().address(address.clone()).build();
+                            let connection_id = opts.connection_id();
+                            match swarm.dial(opts) {
+                                Ok(()) => bootstrap.dialing(index, connection_id),
+                                Err(err) => {
+                                    warn!("Could not dial bootstrap peer {address}: {err}");
+                                    bootstrap.dial_failed(index, now);
+                                }
+                            }
                         }
-                    }
-                    SwarmEvent::Behaviour(MyBehaviourEvent::Mdns(mdns::Event::Expired(list))) => {
-                        for (peer_id, _multiaddr) in list {
-                            info!("mDNS discover peer has expired: {peer_id}");
-                            swarm
-                                .behaviour_mut()
-                                .gossipsub
-                                .remove_explicit_peer(&peer_id);
+                    },
+                    _ = kademlia_interval.tick() => {
+                        if let Some(kademlia) = swarm.behaviour_mut().kademlia.as_mut() {
+                            // fails as long as no peer is known, which is retried on the next tick
+                            let _ = kademlia.bootstrap();
                         }
-                    }
-                    SwarmEvent::NewListenAddr { address, .. } => {
-                        info!("Listening on {address}");
-                    }
-                    SwarmEvent::Behaviour(MyBehaviourEvent::Gossipsub(event)) => {
-                        match incoming_sender.send(event).await {
-                            Ok(v) => v,
-                            Err(err) => {
-                                error!("Could not send on incoming: {err:#?}");
+                    },
+                    _ = score_interval.tick() => {
+                        let now = Instant::now();
+                        let graylisted = swarm
+                            .connected_peers()
+                            .filter(|peer_id| {
+                                swarm
+                                    .behaviour()
+                                    .gossipsub
+                                    .peer_score(peer_id)
+                                    .is_some_and(|score| score < graylist_threshold)
+                            })
+                            .copied()
+                            .collect::<Vec<_>>();
+                        for peer_id in graylisted {
+                            warn!("Disconnecting peer {peer_id} because of its score");
+                            METRICS.peers_banned.inc();
+                            bans.ban(peer_id, now);
+                            swarm.behaviour_mut().gossipsub.remove_explicit_peer(&peer_id);
+                            let _ = swarm.disconnect_peer_id(peer_id);
+                        }
+                    },
+                    outgoing = outgoing_receiver.recv() => match outgoing {
+                        Ok((topic_hash, payload)) => {
+                            let data = match authenticator.seal(&topic_hash, payload) {
+                                Ok(v) => v,
+                                Err(err) => {
+                                    error!("Could not seal outgoing message: {err}");
+                                    continue;
+                                }
+                            };
+                            if let Err(e) = swarm.behaviour_mut().gossipsub.publish(topic_hash, data) {
+                                error!("Swarm publish error: {e:?}");
+                                METRICS.publish_errors.inc();
+                            }
+                        },
+                        Err(err) => error!("Could not listen for outgoing: {err:#?}"),
+                    },
+                    event = swarm.select_next_some() => match event {
+                        SwarmEvent::Behaviour(MyBehaviourEvent::Mdns(mdns::Event::Discovered(list))) => {
+                            for (peer_id, _multiaddr) in list {
+                                add_discovered_peer(&mut swarm, &mut bans, &identity, peer_id, "mDNS");
+                            }
+                        }
+                        SwarmEvent::Behaviour(MyBehaviourEvent::Kademlia(kad::Event::RoutingUpdated {
+                            peer,
+                            is_new_peer: true,
+                            ..
+                        })) => {
+                            add_discovered_peer(&mut swarm, &mut bans, &identity, peer, "Kademlia");
+                        }
+                        SwarmEvent::Behaviour(MyBehaviourEvent::Identify(identify::Event::Received {
+                            peer_id,
+                            info,
+                            ..
+                        })) => {
+                            if let Some(kademlia) = swarm.behaviour_mut().kademlia.as_mut() {
+                                for address in info.listen_addrs {
+                                    kademlia.add_address(&peer_id, address);
+                                }
+                            }
+                        }
+                        SwarmEvent::Behaviour(MyBehaviourEvent::Mdns(mdns::Event::Expired(list))) => {
+                            for (peer_id, _multiaddr) in list {
+                                info!("mDNS discover peer has expired: {peer_id}");
+                                swarm
+                                    .behaviour_mut()
+                                    .gossipsub
+                                    .remove_explicit_peer(&peer_id);
+                                host_info.evict_peer(&peer_id, EvictionReason::MdnsExpired).await;
+                                host_occupation.evict_peer(&peer_id).await;
+                            }
+                        }
+                        SwarmEvent::ConnectionEstablished { peer_id, connection_id, .. }
+                            if bans.is_banned(&peer_id, Instant::now()) || !identity.is_allowed(&peer_id) =>
+                        {
+                            // a bootstrap address which leads to a rejected peer is retried later
+                            bootstrap.connection_failed(connection_id, Instant::now());
+                            let _ = swarm.disconnect_peer_id(peer_id);
+                        }
+                        SwarmEvent::ConnectionEstablished { peer_id, connection_id, .. } => {
+                            if let Some(address) = bootstrap.connected(connection_id, peer_id) {
+                                info!("Connected to bootstrap peer {peer_id} at {address}");
+                                add_discovered_peer(&mut swarm, &mut bans, &identity, peer_id, "Bootstrapping");
+                                if let Some(kademlia) = swarm.behaviour_mut().kademlia.as_mut() {
+                                    kademlia.add_address(&peer_id, address);
+                                    let _ = kademlia.bootstrap();
+                                }
                             }
                         }
+                        SwarmEvent::OutgoingConnectionError { connection_id, .. } => {
+                            bootstrap.connection_failed(connection_id, Instant::now());
+                        }
+                        SwarmEvent::ConnectionClosed { peer_id, num_established: 0, .. } => {
+                            bootstrap.disconnected(&peer_id, Instant::now());
+                            host_info.evict_peer(&peer_id, EvictionReason::ConnectionClosed).await;
+                            host_occupation.evict_peer(&peer_id).await;
+                        }
+                        SwarmEvent::NewListenAddr { address, .. } => {
+                            info!("Listening on {address}");
+                        }
+                        SwarmEvent::Behaviour(MyBehaviourEvent::Gossipsub(gossipsub::Event::Message {
+                            propagation_source,
+                            message_id,
+                            message,
+                        })) => {
+                            let (acceptance, validated) = match validator.validate(&message) {
+                                Ok(v) => (MessageAcceptance::Accept, Some(v)),
+                                Err(acceptance) => (acceptance, None),
+                            };
+                            if let Err(err) = swarm.behaviour_mut().gossipsub.report_message_validation_result(
+                                &message_id,
+                                &propagation_source,
+                                acceptance,
+                            ) {
+                                error!("Could not report validation result: {err:#?}");
+                            }
+
+                            if let Some(validated) = validated {
+                                if let Err(err) = incoming_sender.send(validated).await {
+                                    error!("Could not send on incoming: {err:#?}");
+                                }
+                            }
+                        }
+                        _ => {}
                     }
-                    _ => {}
                 }
             }
         }
@@ -187,21 +696,100 @@ async fn main() -> Result<(), Box<dyn Error>> {
     loop {
         match incoming_receiver.recv().await {
             Ok(incoming) => {
-                if let Some(message) =
-                    extract_topic_message(&incoming, &host_info_instance.topic_hash)
-                {
-                    host_info_instance
-                        .handle_incoming_topic_message(message, &config.token)
-                        .await;
-                } else if let Some(message) =
-                    extract_topic_message(&incoming, &host_occupation_instance.topic_hash)
-                {
-                    host_occupation_instance
-                        .handle_incoming_topic_message(message)
-                        .await;
-                }
+                registry.dispatch(incoming);
             }
             Err(err) => error!("Could not receive incoming message: {err:#?}"),
         }
     }
 }
+
+/// Ticks at the configured evaluation interval, late ticks are not caught up on
+fn evaluation_interval(config: &AppConfig) -> time::Interval {
+    let mut interval = time::interval(Duration::from_secs(
+        config.decision.evaluation_interval_seconds,
+    ));
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    interval
+}
+
+fn configured_host<'a>(
+    config: &'a AppConfig,
+    mac_address: &MacAddress,
+) -> Option<&'a ConfiguredHost> {
+    config.hosts.iter().find(|v| &v.mac_address == mac_address)
+}
+
+/// Listens for quic and tcp connections on the given address
+fn listen(
+    swarm: &mut libp2p::Swarm<MyBehaviour>,
+    host_ip: IpAddr,
+    port: u16,
+) -> Result<Vec<ListenerId>, libp2p::TransportError<io::Error>> {
+    let address = Multiaddr::from(host_ip);
+    let quic = swarm.listen_on(
+        address
+            .clone()
+            .with(Protocol::Udp(port))
+            .with(Protocol::QuicV1),
+    )?;
+    match swarm.listen_on(address.with(Protocol::Tcp(port))) {
+        Ok(tcp) => Ok(vec![quic, tcp]),
+        Err(err) => {
+            // either both or none of the listeners are bound
+            swarm.remove_listener(quic);
+            Err(err)
+        }
+    }
+}
+
+fn update_metrics(snapshot: &ClusterSnapshot) {
+    for metric in Metric::ALL {
+        let labels = MetricLabels {
+            metric: metric.name(),
+        };
+        if let Some(value) = snapshot.local.metric(metric) {
+            METRICS
+                .local_occupation
+                .get_or_create(&labels)
+                .set(value as f64);
+        }
+        if let Some(value) = snapshot.aggregate(metric, Aggregate::Average, Reading::Current) {
+            METRICS
+                .aggregate_occupation
+                .get_or_create(&labels)
+                .set(value as f64);
+        }
+    }
+
+    METRICS.peers.set(snapshot.host_info.len() as i64);
+
+    let (mut awake, mut pending, mut quarantined, mut asleep) = (0, 0, 0, 0);
+    for host in snapshot.configured_hosts {
+        if snapshot
+            .running_mac_addresses()
+            .any(|v| v == &host.mac_address)
+        {
+            awake += 1;
+        } else if snapshot.pending_mac_addresses.contains(&host.mac_address) {
+            pending += 1;
+        } else if snapshot
+            .quarantined_mac_addresses
+            .contains(&host.mac_address)
+        {
+            quarantined += 1;
+        } else {
+            asleep += 1;
+        }
+    }
+    for (state, count) in [
+        ("awake", awake),
+        ("pending", pending),
+        ("quarantined", quarantined),
+        ("asleep", asleep),
+    ] {
+        METRICS
+            .configured_hosts
+            .get_or_create(&HostStateLabels { state })
+            .set(count);
+    }
+}
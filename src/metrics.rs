@@ -0,0 +1,150 @@
+use std::{
+    error::Error,
+    net::SocketAddr,
+    sync::{atomic::AtomicU64, LazyLock},
+};
+
+use axum::{http::header, response::IntoResponse, routing::get, Router};
+use log::{error, info};
+use prometheus_client::{
+    encoding::{text::encode, EncodeLabelSet},
+    metrics::{counter::Counter, family::Family, gauge::Gauge},
+    registry::Registry,
+};
+
+/// All metrics of the daemon, updated from wherever the measured thing happens
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+type FloatGauge = Gauge<f64, AtomicU64>;
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct MetricLabels {
+    pub metric: &'static str,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct HostStateLabels {
+    /// Either awake, pending, quarantined or asleep
+    pub state: &'static str,
+}
+
+pub struct Metrics {
+    registry: Registry,
+    pub local_occupation: Family<MetricLabels, FloatGauge>,
+    pub aggregate_occupation: Family<MetricLabels, FloatGauge>,
+    pub peers: Gauge,
+    /// 1 while this peer holds the leader lease
+    pub leader: Gauge,
+    pub configured_hosts: Family<HostStateLabels, Gauge>,
+    pub magic_packets_sent: Counter,
+    pub magic_packets_failed: Counter,
+    pub wake_retries: Counter,
+    pub messages_rejected: Counter,
+    pub decode_failures: Counter,
+    pub publish_errors: Counter,
+    pub peers_banned: Counter,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let mut metrics = Self {
+            local_occupation: Family::default(),
+            aggregate_occupation: Family::default(),
+            peers: Gauge::default(),
+            leader: Gauge::default(),
+            configured_hosts: Family::default(),
+            magic_packets_sent: Counter::default(),
+            magic_packets_failed: Counter::default(),
+            wake_retries: Counter::default(),
+            messages_rejected: Counter::default(),
+            decode_failures: Counter::default(),
+            publish_errors: Counter::default(),
+            peers_banned: Counter::default(),
+            registry: Registry::with_prefix("dyn_wol"),
+        };
+        let registry = &mut metrics.registry;
+
+        registry.register(
+            "local_occupation",
+            "Occupation of this host by metric",
+            metrics.local_occupation.clone(),
+        );
+        registry.register(
+            "aggregate_occupation",
+            "Average occupation of all known hosts by metric",
+            metrics.aggregate_occupation.clone(),
+        );
+        registry.register("peers", "Number of known peers", metrics.peers.clone());
+        registry.register(
+            "leader",
+            "Whether this peer takes the scaling decisions",
+            metrics.leader.clone(),
+        );
+        registry.register(
+            "configured_hosts",
+            "Number of configured hosts by state",
+            metrics.configured_hosts.clone(),
+        );
+        registry.register(
+            "magic_packets_sent",
+            "Magic packets sent successfully",
+            metrics.magic_packets_sent.clone(),
+        );
+        registry.register(
+            "magic_packets_failed",
+            "Magic packets which could not be sent",
+            metrics.magic_packets_failed.clone(),
+        );
+        registry.register(
+            "wake_retries",
+            "Magic packets sent again because the host did not boot in time",
+            metrics.wake_retries.clone(),
+        );
+        registry.register(
+            "messages_rejected",
+            "Incoming messages rejected because their authentication failed or they were replayed",
+            metrics.messages_rejected.clone(),
+        );
+        registry.register(
+            "decode_failures",
+            "Incoming messages which could not be decoded",
+            metrics.decode_failures.clone(),
+        );
+        registry.register(
+            "publish_errors",
+            "Outgoing messages gossipsub failed to publish",
+            metrics.publish_errors.clone(),
+        );
+        registry.register(
+            "peers_banned",
+            "Peers disconnected because their score fell below the graylist threshold",
+            metrics.peers_banned.clone(),
+        );
+
+        metrics
+    }
+}
+
+pub async fn serve(address: SocketAddr) -> Result<(), Box<dyn Error>> {
+    let router = Router::new().route("/metrics", get(metrics));
+
+    let listener = tokio::net::TcpListener::bind(address).await?;
+    info!("Metrics listening on {address}");
+    axum::serve(listener, router).await?;
+    Ok(())
+}
+
+async fn metrics() -> impl IntoResponse {
+    let mut body = String::new();
+    if let Err(err) = encode(&mut body, &METRICS.registry) {
+        error!("Could not encode metrics: {err:#?}");
+    }
+
+    (
+        [(
+            header::CONTENT_TYPE,
+            "application/openmetrics-text; version=1.0.0; charset=utf-8",
+        )],
+        body,
+    )
+}
@@ -0,0 +1,72 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use libp2p::{
+    gossipsub::{PeerScoreParams, PeerScoreThresholds, TopicHash, TopicScoreParams},
+    PeerId,
+};
+
+use crate::config::PeerScoringConfig;
+
+/// Score parameters which only punish invalid messages. Our topics carry a message every few
+/// seconds, so the delivery rate based penalties of gossipsub would hit honest peers.
+pub fn score_params<'a>(
+    topic_hashes: impl Iterator<Item = &'a TopicHash>,
+    config: &PeerScoringConfig,
+) -> (PeerScoreParams, PeerScoreThresholds) {
+    let topic_params = TopicScoreParams {
+        topic_weight: 1.0,
+        time_in_mesh_weight: 0.0,
+        first_message_deliveries_weight: 0.0,
+        mesh_message_deliveries_weight: 0.0,
+        mesh_failure_penalty_weight: 0.0,
+        // squared, so a few invalid messages in a row are enough to reach the graylist
+        invalid_message_deliveries_weight: -10.0,
+        invalid_message_deliveries_decay: 0.9,
+        ..Default::default()
+    };
+
+    let params = PeerScoreParams {
+        topics: topic_hashes
+            .map(|v| (v.clone(), topic_params.clone()))
+            .collect(),
+        // the hosts of a cluster often share an address behind a nat
+        ip_colocation_factor_weight: 0.0,
+        ..Default::default()
+    };
+
+    let graylist = config.graylist_threshold;
+    let thresholds = PeerScoreThresholds {
+        gossip_threshold: graylist / 8.0,
+        publish_threshold: graylist * 5.0 / 8.0,
+        graylist_threshold: graylist,
+        ..Default::default()
+    };
+    (params, thresholds)
+}
+
+/// Peers we disconnected because of their score, which must not be connected again for a while
+pub struct Bans {
+    duration: Duration,
+    until: HashMap<PeerId, Instant>,
+}
+
+impl Bans {
+    pub fn new(duration: Duration) -> Self {
+        Self {
+            duration,
+            until: HashMap::new(),
+        }
+    }
+
+    pub fn ban(&mut self, peer_id: PeerId, now: Instant) {
+        self.until.insert(peer_id, now + self.duration);
+    }
+
+    pub fn is_banned(&mut self, peer_id: &PeerId, now: Instant) -> bool {
+        self.until.retain(|_, until| now < *until);
+        self.until.contains_key(peer_id)
+    }
+}
@@ -0,0 +1,72 @@
+use super::{ClusterSnapshot, ScalingDecision, ScalingPolicy};
+
+/// Wakes a host as soon as any single host exceeds the threshold
+pub struct MaxOfAny {
+    pub occupation_level: f32,
+    pub scale_down_level: Option<f32>,
+}
+
+impl ScalingPolicy for MaxOfAny {
+    fn decide(&self, snapshot: &ClusterSnapshot) -> ScalingDecision {
+        let max = snapshot.cpu_percentages().fold(0f32, f32::max);
+
+        if max > self.occupation_level {
+            return ScalingDecision::wake(snapshot.wake_candidates().into_iter().take(1).collect());
+        }
+
+        match self.scale_down_level {
+            Some(level) if max < level => ScalingDecision::Suspend(
+                snapshot.suspend_candidates().into_iter().take(1).collect(),
+            ),
+            _ => ScalingDecision::Nothing,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policy::tests::{mac, Cluster};
+
+    fn policy() -> MaxOfAny {
+        MaxOfAny {
+            occupation_level: 80.0,
+            scale_down_level: Some(20.0),
+        }
+    }
+
+    #[test]
+    fn wakes_when_a_single_host_is_busy() {
+        let mut cluster = Cluster::new(10.0, 2);
+        cluster.run(1, 95.0);
+
+        assert_eq!(
+            policy().decide(&cluster.snapshot()),
+            ScalingDecision::Wake(vec![mac(2)])
+        );
+    }
+
+    #[test]
+    fn holds_without_a_host_to_wake() {
+        let mut cluster = Cluster::new(10.0, 1);
+        cluster.run(1, 95.0);
+
+        assert_eq!(policy().decide(&cluster.snapshot()), ScalingDecision::Hold);
+    }
+
+    #[test]
+    fn suspends_only_when_every_host_is_idle() {
+        let mut cluster = Cluster::new(10.0, 2);
+        let idle = cluster.run(1, 5.0);
+        assert_eq!(
+            policy().decide(&cluster.snapshot()),
+            ScalingDecision::Suspend(vec![idle])
+        );
+
+        cluster.run(2, 30.0);
+        assert_eq!(
+            policy().decide(&cluster.snapshot()),
+            ScalingDecision::Nothing
+        );
+    }
+}
@@ -0,0 +1,298 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Local};
+use libp2p::PeerId;
+use mac_address::MacAddress;
+
+use crate::{
+    config::{Aggregate, AppConfig, ConfiguredHost, Metric, PolicyConfig, Reading, WakeRule},
+    host_selection::order_wake_candidates,
+    topics::{
+        host_info::OtherHost,
+        host_occupation::{HostOccupation, HostOccupationMessage, OtherHostOccupation},
+    },
+};
+
+pub mod max_of_any;
+pub mod schedule;
+pub mod target_utilisation;
+pub mod threshold_average;
+
+/// Everything a policy may look at to come to a decision
+pub struct ClusterSnapshot<'a> {
+    pub host_info: &'a HashMap<PeerId, OtherHost>,
+    pub host_occupation: &'a HashMap<PeerId, OtherHostOccupation>,
+    pub local: &'a HostOccupationMessage,
+    pub configured_hosts: &'a [ConfiguredHost],
+    /// Hosts which have been woken but did not show up yet
+    pub pending_mac_addresses: &'a [MacAddress],
+    /// Hosts which did not come up after being woken repeatedly
+    pub quarantined_mac_addresses: &'a [MacAddress],
+    /// Hosts we woke on our own, the only ones which may be suspended
+    pub woken_mac_addresses: &'a [MacAddress],
+    pub now: DateTime<Local>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ScalingDecision {
+    Wake(Vec<MacAddress>),
+    Suspend(Vec<PeerId>),
+    /// More hosts are needed but there is none left which could be woken
+    Hold,
+    Nothing,
+}
+
+impl ScalingDecision {
+    /// Wakes the given hosts, or holds if there are none
+    fn wake(mac_addresses: Vec<MacAddress>) -> Self {
+        if mac_addresses.is_empty() {
+            return ScalingDecision::Hold;
+        }
+        ScalingDecision::Wake(mac_addresses)
+    }
+}
+
+pub trait ScalingPolicy: Send + Sync {
+    fn decide(&self, snapshot: &ClusterSnapshot) -> ScalingDecision;
+}
+
+pub fn from_config(config: &AppConfig) -> Box<dyn ScalingPolicy> {
+    let scale_down_level = config
+        .scale_down
+        .enabled
+        .then_some(config.scale_down.occupation_level_percentage as f32);
+
+    match &config.policy {
+        PolicyConfig::ThresholdAverage => {
+            let mut rules = config.wake_rules.rules.clone();
+            if rules.is_empty() {
+                rules.push(WakeRule {
+                    metric: Metric::CpuPercentage,
+                    aggregate: Aggregate::Average,
+                    above: config.occupation_level_percentage as f32,
+                    reading: Reading::Current,
+                });
+            }
+            Box::new(threshold_average::ThresholdAverage {
+                rules,
+                combine: config.wake_rules.combine,
+                scale_down_level,
+            })
+        }
+        PolicyConfig::MaxOfAny => Box::new(max_of_any::MaxOfAny {
+            occupation_level: config.occupation_level_percentage as f32,
+            scale_down_level,
+        }),
+        PolicyConfig::TargetUtilisation { target_percentage } => {
+            Box::new(target_utilisation::TargetUtilisation {
+                target_level: *target_percentage as f32,
+            })
+        }
+        PolicyConfig::Schedule { windows } => Box::new(schedule::Schedule {
+            windows: windows.clone(),
+        }),
+    }
+}
+
+impl ClusterSnapshot<'_> {
+    /// The cpu readings of all known hosts including the local one
+    pub fn cpu_percentages(&self) -> impl Iterator<Item = f32> + '_ {
+        self.host_occupation
+            .values()
+            .map(|v| v.occupation.cpu_percentage)
+            .chain(std::iter::once(self.local.cpu_percentage))
+    }
+
+    pub fn average_cpu_percentage(&self) -> f32 {
+        HostOccupation::calculate_total_occupation(self.host_occupation, self.local).cpu_percentage
+    }
+
+    /// Aggregates a metric over all hosts which report it, None if no host does
+    pub fn aggregate(&self, metric: Metric, aggregate: Aggregate, reading: Reading) -> Option<f32> {
+        let values = self
+            .host_occupation
+            .values()
+            .map(|v| &v.occupation)
+            .chain(std::iter::once(self.local))
+            .filter_map(|v| v.reading(reading).metric(metric))
+            .collect::<Vec<_>>();
+        if values.is_empty() {
+            return None;
+        }
+
+        Some(match aggregate {
+            Aggregate::Average => values.iter().sum::<f32>() / values.len() as f32,
+            Aggregate::Max => values.into_iter().fold(f32::MIN, f32::max),
+        })
+    }
+
+    pub fn running_mac_addresses(&self) -> impl Iterator<Item = &MacAddress> + '_ {
+        self.host_info.values().map(|v| &v.mac_address)
+    }
+
+    /// Configured hosts which are neither running, about to come up nor quarantined, in the
+    /// order they should be woken
+    pub fn wake_candidates(&self) -> Vec<MacAddress> {
+        let excluded = self
+            .running_mac_addresses()
+            .chain(self.pending_mac_addresses)
+            .chain(self.quarantined_mac_addresses)
+            .copied()
+            .collect::<Vec<_>>();
+        order_wake_candidates(self.configured_hosts, &excluded)
+            .into_iter()
+            .map(|v| v.mac_address)
+            .collect()
+    }
+
+    /// Peers which we woke and can wake again, the most idle one first. Hosts which are not
+    /// configured or which were started by someone else are considered pinned and never get
+    /// suspended.
+    pub fn suspend_candidates(&self) -> Vec<PeerId> {
+        let mut candidates = self
+            .host_occupation
+            .iter()
+            .filter(|(peer_id, _)| {
+                self.host_info.get(peer_id).is_some_and(|info| {
+                    self.woken_mac_addresses.contains(&info.mac_address)
+                        && self
+                            .configured_hosts
+                            .iter()
+                            .any(|host| host.mac_address == info.mac_address)
+                })
+            })
+            .collect::<Vec<_>>();
+        candidates.sort_by(|a, b| {
+            a.1.occupation
+                .cpu_percentage
+                .total_cmp(&b.1.occupation.cpu_percentage)
+        });
+        candidates.into_iter().map(|v| *v.0).collect()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::time::Instant;
+
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::topics::PROTOCOL_VERSION;
+
+    pub fn mac(last_byte: u8) -> MacAddress {
+        MacAddress::new([0, 0, 0, 0, 0, last_byte])
+    }
+
+    /// Owns everything a snapshot borrows
+    pub struct Cluster {
+        pub host_info: HashMap<PeerId, OtherHost>,
+        pub host_occupation: HashMap<PeerId, OtherHostOccupation>,
+        pub local: HostOccupationMessage,
+        pub configured_hosts: Vec<ConfiguredHost>,
+        pub pending_mac_addresses: Vec<MacAddress>,
+        pub quarantined_mac_addresses: Vec<MacAddress>,
+        pub woken_mac_addresses: Vec<MacAddress>,
+        pub now: DateTime<Local>,
+    }
+
+    impl Cluster {
+        /// A cluster of only us, with hosts `host-1` to `host-<configured>` which are all down
+        /// and were all woken by us before
+        pub fn new(local_cpu_percentage: f32, configured: u8) -> Self {
+            Self {
+                host_info: HashMap::new(),
+                host_occupation: HashMap::new(),
+                local: HostOccupationMessage {
+                    cpu_percentage: local_cpu_percentage,
+                    ..Default::default()
+                },
+                configured_hosts: (1..=configured)
+                    .map(|v| ConfiguredHost {
+                        name: format!("host-{v}"),
+                        mac_address: mac(v),
+                        ..Default::default()
+                    })
+                    .collect(),
+                pending_mac_addresses: Vec::new(),
+                quarantined_mac_addresses: Vec::new(),
+                woken_mac_addresses: (1..=configured).map(mac).collect(),
+                now: Local.with_ymd_and_hms(2026, 10, 14, 12, 0, 0).unwrap(),
+            }
+        }
+
+        /// Lets the host with the given mac address join the cluster
+        pub fn run(&mut self, last_byte: u8, cpu_percentage: f32) -> PeerId {
+            let peer_id = PeerId::random();
+            self.host_info.insert(
+                peer_id,
+                OtherHost {
+                    name: format!("host-{last_byte}"),
+                    mac_address: mac(last_byte),
+                    last_seen: Instant::now(),
+                    protocol_version: PROTOCOL_VERSION,
+                    capabilities: Vec::new(),
+                },
+            );
+            self.host_occupation.insert(
+                peer_id,
+                OtherHostOccupation {
+                    occupation: HostOccupationMessage {
+                        cpu_percentage,
+                        ..Default::default()
+                    },
+                    last_seen: Instant::now(),
+                },
+            );
+            peer_id
+        }
+
+        pub fn snapshot(&self) -> ClusterSnapshot<'_> {
+            ClusterSnapshot {
+                host_info: &self.host_info,
+                host_occupation: &self.host_occupation,
+                local: &self.local,
+                configured_hosts: &self.configured_hosts,
+                pending_mac_addresses: &self.pending_mac_addresses,
+                quarantined_mac_addresses: &self.quarantined_mac_addresses,
+                woken_mac_addresses: &self.woken_mac_addresses,
+                now: self.now,
+            }
+        }
+    }
+
+    #[test]
+    fn aggregates_peak_readings() {
+        let mut cluster = Cluster::new(20.0, 2);
+        cluster.local.peak = Some(Box::new(HostOccupationMessage {
+            cpu_percentage: 80.0,
+            ..Default::default()
+        }));
+        // a peer which does not report peaks counts with its current reading
+        cluster.run(1, 40.0);
+
+        let snapshot = cluster.snapshot();
+        let max = |reading| snapshot.aggregate(Metric::CpuPercentage, Aggregate::Max, reading);
+        assert_eq!(max(Reading::Current), Some(40.0));
+        assert_eq!(max(Reading::Peak), Some(80.0));
+        assert_eq!(
+            snapshot.aggregate(Metric::CpuPercentage, Aggregate::Average, Reading::Peak),
+            Some(60.0)
+        );
+    }
+
+    #[test]
+    fn leaves_out_hosts_which_are_not_ours() {
+        let mut cluster = Cluster::new(50.0, 3);
+        let ours = cluster.run(1, 10.0);
+        cluster.run(9, 0.0);
+        cluster.quarantined_mac_addresses.push(mac(2));
+        // started by someone else
+        cluster.run(3, 0.0);
+        cluster.woken_mac_addresses.retain(|v| v != &mac(3));
+
+        let snapshot = cluster.snapshot();
+        assert_eq!(snapshot.suspend_candidates(), [ours]);
+        assert!(snapshot.wake_candidates().is_empty());
+    }
+}
@@ -0,0 +1,150 @@
+use chrono::Datelike;
+
+use crate::config::ScheduleWindow;
+
+use super::{ClusterSnapshot, ScalingDecision, ScalingPolicy};
+
+/// Keeps the named hosts awake during the configured windows and suspends them outside
+pub struct Schedule {
+    pub windows: Vec<ScheduleWindow>,
+}
+
+impl Schedule {
+    fn is_active(window: &ScheduleWindow, snapshot: &ClusterSnapshot) -> bool {
+        let time = snapshot.now.time();
+        let in_time = if window.from <= window.until {
+            window.from <= time && time < window.until
+        } else {
+            // the window spans midnight
+            window.from <= time || time < window.until
+        };
+        in_time && (window.days.is_empty() || window.days.contains(&snapshot.now.weekday()))
+    }
+}
+
+impl ScalingPolicy for Schedule {
+    fn decide(&self, snapshot: &ClusterSnapshot) -> ScalingDecision {
+        let scheduled = |active: bool| {
+            self.windows
+                .iter()
+                .filter(move |window| Self::is_active(window, snapshot) == active)
+                .flat_map(|window| window.hosts.iter())
+                .flat_map(|name| {
+                    snapshot
+                        .configured_hosts
+                        .iter()
+                        .filter(move |v| &v.name == name || v.tags.contains(name))
+                })
+                .map(|v| v.mac_address)
+                .collect::<Vec<_>>()
+        };
+        let wanted = scheduled(true);
+        let unwanted = scheduled(false);
+
+        let to_wake = snapshot
+            .wake_candidates()
+            .into_iter()
+            .filter(|v| wanted.contains(v))
+            .collect::<Vec<_>>();
+        if !to_wake.is_empty() {
+            return ScalingDecision::Wake(to_wake);
+        }
+
+        let to_suspend = snapshot
+            .host_info
+            .iter()
+            .filter(|(_, info)| {
+                unwanted.contains(&info.mac_address) && !wanted.contains(&info.mac_address)
+            })
+            .map(|(peer_id, _)| *peer_id)
+            .collect::<Vec<_>>();
+        if !to_suspend.is_empty() {
+            return ScalingDecision::Suspend(to_suspend);
+        }
+
+        ScalingDecision::Nothing
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{NaiveTime, Weekday};
+
+    use super::*;
+    use crate::policy::tests::{mac, Cluster};
+
+    fn window(from: u32, until: u32, hosts: &[&str]) -> ScheduleWindow {
+        ScheduleWindow {
+            days: Vec::new(),
+            from: NaiveTime::from_hms_opt(from, 0, 0).unwrap(),
+            until: NaiveTime::from_hms_opt(until, 0, 0).unwrap(),
+            hosts: hosts.iter().map(|v| v.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn wakes_the_hosts_of_active_windows() {
+        let mut cluster = Cluster::new(0.0, 3);
+        cluster.configured_hosts[2].tags.push("office".into());
+        let schedule = Schedule {
+            windows: vec![
+                window(8, 18, &["host-1", "office"]),
+                window(18, 20, &["host-2"]),
+            ],
+        };
+
+        assert_eq!(
+            schedule.decide(&cluster.snapshot()),
+            ScalingDecision::Wake(vec![mac(1), mac(3)])
+        );
+    }
+
+    #[test]
+    fn respects_windows_spanning_midnight_and_weekdays() {
+        let cluster = Cluster::new(0.0, 2);
+        // the snapshot is taken on a wednesday at noon
+        let schedule = Schedule {
+            windows: vec![
+                window(22, 13, &["host-1"]),
+                ScheduleWindow {
+                    days: vec![Weekday::Sat, Weekday::Sun],
+                    ..window(8, 18, &["host-2"])
+                },
+            ],
+        };
+
+        assert_eq!(
+            schedule.decide(&cluster.snapshot()),
+            ScalingDecision::Wake(vec![mac(1)])
+        );
+    }
+
+    #[test]
+    fn suspends_hosts_outside_their_windows() {
+        let mut cluster = Cluster::new(0.0, 2);
+        let unwanted = cluster.run(1, 0.0);
+        cluster.run(2, 0.0);
+        let schedule = Schedule {
+            windows: vec![window(18, 20, &["host-1"]), window(8, 18, &["host-2"])],
+        };
+
+        assert_eq!(
+            schedule.decide(&cluster.snapshot()),
+            ScalingDecision::Suspend(vec![unwanted])
+        );
+    }
+
+    #[test]
+    fn does_nothing_without_windows() {
+        let mut cluster = Cluster::new(100.0, 2);
+        cluster.run(1, 0.0);
+
+        assert_eq!(
+            Schedule {
+                windows: Vec::new()
+            }
+            .decide(&cluster.snapshot()),
+            ScalingDecision::Nothing
+        );
+    }
+}
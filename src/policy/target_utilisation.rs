@@ -0,0 +1,97 @@
+use super::{ClusterSnapshot, ScalingDecision, ScalingPolicy};
+
+/// Sizes the cluster so the average occupation ends up close to the target
+pub struct TargetUtilisation {
+    pub target_level: f32,
+}
+
+impl ScalingPolicy for TargetUtilisation {
+    fn decide(&self, snapshot: &ClusterSnapshot) -> ScalingDecision {
+        let running = snapshot.host_occupation.len() + 1;
+        let total = snapshot.cpu_percentages().sum::<f32>();
+        let desired = ((total / self.target_level).ceil() as usize).max(1);
+
+        // hosts which are still booting will take load soon, so they count as running
+        let expected = running + snapshot.pending_mac_addresses.len();
+        if desired > expected {
+            return ScalingDecision::wake(
+                snapshot
+                    .wake_candidates()
+                    .into_iter()
+                    .take(desired - expected)
+                    .collect(),
+            );
+        }
+
+        if desired < running && snapshot.pending_mac_addresses.is_empty() {
+            return ScalingDecision::Suspend(
+                snapshot
+                    .suspend_candidates()
+                    .into_iter()
+                    .take(running - desired)
+                    .collect(),
+            );
+        }
+
+        ScalingDecision::Nothing
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policy::tests::{mac, Cluster};
+
+    fn policy() -> TargetUtilisation {
+        TargetUtilisation { target_level: 50.0 }
+    }
+
+    #[test]
+    fn wakes_as_many_hosts_as_the_load_needs() {
+        let mut cluster = Cluster::new(90.0, 3);
+        cluster.run(1, 90.0);
+
+        // 180% of load need 4 hosts at 50%, two more than are running
+        assert_eq!(
+            policy().decide(&cluster.snapshot()),
+            ScalingDecision::Wake(vec![mac(2), mac(3)])
+        );
+    }
+
+    #[test]
+    fn counts_pending_hosts_as_running() {
+        let mut cluster = Cluster::new(90.0, 2);
+        cluster.pending_mac_addresses.push(mac(1));
+
+        assert_eq!(
+            policy().decide(&cluster.snapshot()),
+            ScalingDecision::Nothing
+        );
+    }
+
+    #[test]
+    fn holds_without_a_host_to_wake() {
+        let cluster = Cluster::new(90.0, 0);
+
+        assert_eq!(policy().decide(&cluster.snapshot()), ScalingDecision::Hold);
+    }
+
+    #[test]
+    fn suspends_the_hosts_which_are_not_needed() {
+        let mut cluster = Cluster::new(10.0, 2);
+        let busier = cluster.run(1, 20.0);
+        let idle = cluster.run(2, 5.0);
+
+        // 35% of load fit on a single host
+        assert_eq!(
+            policy().decide(&cluster.snapshot()),
+            ScalingDecision::Suspend(vec![idle, busier])
+        );
+
+        cluster.pending_mac_addresses.push(mac(3));
+        assert_eq!(
+            policy().decide(&cluster.snapshot()),
+            ScalingDecision::Nothing
+        );
+    }
+}
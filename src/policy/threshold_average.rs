@@ -0,0 +1,137 @@
+use crate::config::{Combine, WakeRule};
+
+use super::{ClusterSnapshot, ScalingDecision, ScalingPolicy};
+
+/// Wakes a host when the aggregated occupation of all hosts exceeds the configured rules,
+/// by default when the average cpu percentage exceeds the threshold
+pub struct ThresholdAverage {
+    pub rules: Vec<WakeRule>,
+    pub combine: Combine,
+    pub scale_down_level: Option<f32>,
+}
+
+impl ThresholdAverage {
+    fn rule_matches(rule: &WakeRule, snapshot: &ClusterSnapshot) -> bool {
+        snapshot
+            .aggregate(rule.metric, rule.aggregate, rule.reading)
+            .is_some_and(|v| v > rule.above)
+    }
+}
+
+impl ScalingPolicy for ThresholdAverage {
+    fn decide(&self, snapshot: &ClusterSnapshot) -> ScalingDecision {
+        let breached = match self.combine {
+            Combine::Any => self.rules.iter().any(|v| Self::rule_matches(v, snapshot)),
+            Combine::All => self.rules.iter().all(|v| Self::rule_matches(v, snapshot)),
+        };
+
+        if breached {
+            return ScalingDecision::wake(snapshot.wake_candidates().into_iter().take(1).collect());
+        }
+
+        match self.scale_down_level {
+            Some(level) if snapshot.average_cpu_percentage() < level => ScalingDecision::Suspend(
+                snapshot.suspend_candidates().into_iter().take(1).collect(),
+            ),
+            _ => ScalingDecision::Nothing,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::{Aggregate, Metric, Reading},
+        policy::tests::{mac, Cluster},
+    };
+
+    fn policy() -> ThresholdAverage {
+        ThresholdAverage {
+            rules: vec![WakeRule {
+                metric: Metric::CpuPercentage,
+                aggregate: Aggregate::Average,
+                above: 80.0,
+                reading: Reading::Current,
+            }],
+            combine: Combine::Any,
+            scale_down_level: Some(20.0),
+        }
+    }
+
+    #[test]
+    fn wakes_one_host_above_the_threshold() {
+        let mut cluster = Cluster::new(90.0, 3);
+        cluster.run(1, 90.0);
+
+        assert_eq!(
+            policy().decide(&cluster.snapshot()),
+            ScalingDecision::Wake(vec![mac(2)])
+        );
+    }
+
+    #[test]
+    fn holds_without_a_host_to_wake() {
+        let mut cluster = Cluster::new(90.0, 2);
+        cluster.run(1, 90.0);
+        cluster.pending_mac_addresses.push(mac(2));
+
+        assert_eq!(policy().decide(&cluster.snapshot()), ScalingDecision::Hold);
+    }
+
+    #[test]
+    fn suspends_the_most_idle_host_below_the_scale_down_level() {
+        let mut cluster = Cluster::new(10.0, 2);
+        cluster.run(1, 15.0);
+        let idle = cluster.run(2, 5.0);
+
+        assert_eq!(
+            policy().decide(&cluster.snapshot()),
+            ScalingDecision::Suspend(vec![idle])
+        );
+
+        let without_scale_down = ThresholdAverage {
+            scale_down_level: None,
+            ..policy()
+        };
+        assert_eq!(
+            without_scale_down.decide(&cluster.snapshot()),
+            ScalingDecision::Nothing
+        );
+    }
+
+    #[test]
+    fn combines_the_rules() {
+        let mut cluster = Cluster::new(90.0, 2);
+        cluster.run(1, 50.0);
+        let rules = vec![
+            WakeRule {
+                metric: Metric::CpuPercentage,
+                aggregate: Aggregate::Max,
+                above: 80.0,
+                reading: Reading::Current,
+            },
+            WakeRule {
+                metric: Metric::CpuPercentage,
+                aggregate: Aggregate::Average,
+                above: 80.0,
+                reading: Reading::Current,
+            },
+        ];
+
+        let any = ThresholdAverage {
+            rules: rules.clone(),
+            ..policy()
+        };
+        assert_eq!(
+            any.decide(&cluster.snapshot()),
+            ScalingDecision::Wake(vec![mac(2)])
+        );
+        let all = ThresholdAverage {
+            rules,
+            combine: Combine::All,
+            ..policy()
+        };
+        assert_eq!(all.decide(&cluster.snapshot()), ScalingDecision::Nothing);
+    }
+}
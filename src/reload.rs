@@ -0,0 +1,179 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use log::{info, warn};
+use tokio::{select, sync::watch, time};
+
+use crate::config::{read_config, AppConfig};
+
+/// The config the daemon currently runs with, replaced as a whole on every accepted reload
+pub type LiveConfig = watch::Receiver<Arc<AppConfig>>;
+
+/// How often the config file is checked for changes
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// The extensions the config crate tries when the path has none
+const EXTENSIONS: [&str; 7] = ["toml", "json", "yaml", "yml", "ini", "ron", "json5"];
+
+/// Reloads the config whenever the file changes or we receive a SIGHUP
+pub fn watch_config(path: &str, config: AppConfig) -> LiveConfig {
+    let (sender, receiver) = watch::channel(Arc::new(config));
+    let path = path.to_string();
+
+    tokio::spawn(async move {
+        let mut hangup = Hangup::new();
+        let mut interval = time::interval(POLL_INTERVAL);
+        let mut modified = modified_at(&path);
+        loop {
+            select! {
+                _ = interval.tick() => {
+                    let now_modified = modified_at(&path);
+                    if now_modified == modified {
+                        continue;
+                    }
+                    modified = now_modified;
+                    info!("The config file changed, reloading it");
+                }
+                _ = hangup.recv() => info!("Received SIGHUP, reloading the config"),
+            }
+            reload(&path, &sender);
+        }
+    });
+
+    receiver
+}
+
+fn reload(path: &str, sender: &watch::Sender<Arc<AppConfig>>) {
+    let mut config = match read_config(path) {
+        Ok(v) => v,
+        Err(err) => {
+            warn!("Rejected the new config, keeping the old one:\n{err}");
+            return;
+        }
+    };
+
+    let current = sender.borrow().clone();
+    for section in keep_restart_only(&current, &mut config) {
+        warn!("Changes to {section} only apply after a restart");
+    }
+    if *current == config {
+        info!("The config did not change");
+        return;
+    }
+    sender.send_replace(Arc::new(config));
+    info!("Applied the new config");
+}
+
+/// Keeps the old values of the sections which are only read at startup, so the live config
+/// always matches what the daemon runs with. Returns the sections which changed.
+fn keep_restart_only(old: &AppConfig, new: &mut AppConfig) -> Vec<&'static str> {
+    let mut kept = Vec::new();
+    macro_rules! keep {
+        ($($field:ident),*) => {
+            $(
+                if new.$field != old.$field {
+                    new.$field = old.$field.clone();
+                    kept.push(stringify!($field));
+                }
+            )*
+        };
+    }
+    keep!(
+        identity,
+        discovery,
+        election,
+        peer_scoring,
+        codec,
+        api,
+        metrics
+    );
+    kept
+}
+
+fn modified_at(path: &str) -> Option<SystemTime> {
+    config_file(path)?.metadata().ok()?.modified().ok()
+}
+
+fn config_file(path: &str) -> Option<PathBuf> {
+    let path = Path::new(path);
+    if path.is_file() {
+        return Some(path.to_path_buf());
+    }
+    EXTENSIONS
+        .iter()
+        .map(|v| path.with_extension(v))
+        .find(|v| v.is_file())
+}
+
+/// Resolves on every SIGHUP, never on platforms without signals
+struct Hangup {
+    #[cfg(unix)]
+    signal: Option<tokio::signal::unix::Signal>,
+}
+
+impl Hangup {
+    #[cfg(unix)]
+    fn new() -> Self {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let signal = signal(SignalKind::hangup())
+            .inspect_err(|err| warn!("Could not listen for SIGHUP: {err:#?}"))
+            .ok();
+        Self { signal }
+    }
+
+    #[cfg(not(unix))]
+    fn new() -> Self {
+        Self {}
+    }
+
+    async fn recv(&mut self) {
+        #[cfg(unix)]
+        if let Some(signal) = &mut self.signal {
+            if signal.recv().await.is_some() {
+                return;
+            }
+            self.signal = None;
+        }
+        std::future::pending::<()>().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{TokenKey, Tokens};
+    use libp2p::PeerId;
+
+    #[test]
+    fn keeps_sections_which_need_a_restart() {
+        let old = AppConfig {
+            token: Tokens(vec![TokenKey {
+                id: "old".into(),
+                secret: "old secret".into(),
+                not_after: None,
+            }]),
+            ..Default::default()
+        };
+        let mut new = AppConfig {
+            token: Tokens(vec![TokenKey {
+                id: "new".into(),
+                secret: "new secret".into(),
+                not_after: None,
+            }]),
+            occupation_level_percentage: 50,
+            ..Default::default()
+        };
+        new.identity.allowed_peers = vec![PeerId::random()];
+        new.api.enabled = true;
+
+        assert_eq!(keep_restart_only(&old, &mut new), ["identity", "api"]);
+        assert_eq!(new.identity, old.identity);
+        assert_eq!(new.api, old.api);
+        assert_eq!(new.token.signing().unwrap().id, "new");
+        assert_eq!(new.occupation_level_percentage, 50);
+    }
+}
@@ -1,33 +1,20 @@
+use crate::{config::ConfiguredHost, magic_packet::send_magic_packet, metrics::METRICS};
 use log::error;
-// TODO we dont really need the mac_address crate with sysinfo
-use mac_address::MacAddress;
-use rand::seq::SliceRandom;
 
-pub fn send_activation_action(
-    already_running_mac_addresses: Vec<MacAddress>,
-    available_mac_addresses: Vec<MacAddress>,
-) {
-    let non_stated_mac_addresses = available_mac_addresses
-        .iter()
-        .filter(|mac_address| !already_running_mac_addresses.contains(mac_address))
-        .collect::<Vec<_>>();
-    // A random non started mac address
-    let mac_address = match non_stated_mac_addresses.choose(&mut rand::thread_rng()) {
-        Some(v) => **v,
-        None => {
-            error!("Could not find any mac address to send the activation action to");
-            return;
+/// Sends a magic packet to the given host, returns whether sending succeeded
+pub async fn send_activation_action(host: &ConfiguredHost) -> bool {
+    match send_magic_packet(&host.mac_address, &host.wol).await {
+        Ok(_) => {
+            METRICS.magic_packets_sent.inc();
+            true
         }
-    };
-
-    // Create a magic packet (but don't send it yet)
-    let magic_packet = wake_on_lan::MagicPacket::new(&mac_address.bytes());
-
-    // Send the magic packet via UDP to the broadcast address 255.255.255.255:9 from 0.0.0.0:0
-    match magic_packet.send() {
-        Ok(v) => v,
         Err(err) => {
-            error!("Could not send magic packet: {err:#?}");
-        },
-    };
+            error!(
+                "Could not send magic packet to {} ({}): {err:#?}",
+                host.name, host.mac_address
+            );
+            METRICS.magic_packets_failed.inc();
+            false
+        }
+    }
 }
@@ -0,0 +1,225 @@
+//! The encodings of the messages inside the envelopes. From protocol version 2 on every payload
+//! starts with a tag byte naming its encoding, so peers configured with different encodings can
+//! talk to each other while a cluster migrates. The protobuf schema for tools which are not
+//! written in rust is in `proto/dyn_wol.proto`.
+
+use mac_address::MacAddress;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::config::{CodecConfig, Encoding};
+
+/// Payloads of envelopes from before this version are untagged flexbuffers
+pub const TAGGED_SINCE_VERSION: u16 = 2;
+
+/// Set in the tag byte when the encoded message is compressed with zstd
+const COMPRESSED: u8 = 0x80;
+const ZSTD_LEVEL: i32 = 3;
+/// Compressed messages which would grow larger than this are rejected
+const MAX_DECOMPRESSED_SIZE: usize = 1 << 20;
+
+/// A message which can be sent in every encoding
+pub trait WireMessage: Serialize + DeserializeOwned + Send + Sync {
+    /// The protobuf representation of the message
+    type Proto: prost::Message + Default;
+
+    fn to_proto(&self) -> Self::Proto;
+
+    fn from_proto(proto: Self::Proto) -> Result<Self, String>;
+}
+
+/// Turns messages into bytes and back
+pub trait Codec {
+    fn encode<T: WireMessage>(message: &T) -> Result<Vec<u8>, String>;
+
+    fn decode<T: WireMessage>(bytes: &[u8]) -> Result<T, String>;
+}
+
+pub struct Flexbuffers;
+
+impl Codec for Flexbuffers {
+    fn encode<T: WireMessage>(message: &T) -> Result<Vec<u8>, String> {
+        let mut s = flexbuffers::FlexbufferSerializer::new();
+        message.serialize(&mut s).map_err(|err| err.to_string())?;
+        Ok(s.view().into())
+    }
+
+    fn decode<T: WireMessage>(bytes: &[u8]) -> Result<T, String> {
+        let r = flexbuffers::Reader::get_root(bytes).map_err(|err| err.to_string())?;
+        T::deserialize(r).map_err(|err| err.to_string())
+    }
+}
+
+pub struct Cbor;
+
+impl Codec for Cbor {
+    fn encode<T: WireMessage>(message: &T) -> Result<Vec<u8>, String> {
+        let mut bytes = Vec::new();
+        ciborium::into_writer(message, &mut bytes).map_err(|err| err.to_string())?;
+        Ok(bytes)
+    }
+
+    fn decode<T: WireMessage>(bytes: &[u8]) -> Result<T, String> {
+        ciborium::from_reader(bytes).map_err(|err| err.to_string())
+    }
+}
+
+pub struct Protobuf;
+
+impl Codec for Protobuf {
+    fn encode<T: WireMessage>(message: &T) -> Result<Vec<u8>, String> {
+        Ok(prost::Message::encode_to_vec(&message.to_proto()))
+    }
+
+    fn decode<T: WireMessage>(bytes: &[u8]) -> Result<T, String> {
+        let proto = <T::Proto as prost::Message>::decode(bytes).map_err(|err| err.to_string())?;
+        T::from_proto(proto)
+    }
+}
+
+impl Encoding {
+    fn tag(self) -> u8 {
+        match self {
+            Encoding::Flexbuffers => 0,
+            Encoding::Cbor => 1,
+            Encoding::Protobuf => 2,
+        }
+    }
+
+    fn from_tag(tag: u8) -> Option<Self> {
+        match tag {
+            0 => Some(Encoding::Flexbuffers),
+            1 => Some(Encoding::Cbor),
+            2 => Some(Encoding::Protobuf),
+            _ => None,
+        }
+    }
+
+    fn encode<T: WireMessage>(self, message: &T) -> Result<Vec<u8>, String> {
+        match self {
+            Encoding::Flexbuffers => Flexbuffers::encode(message),
+            Encoding::Cbor => Cbor::encode(message),
+            Encoding::Protobuf => Protobuf::encode(message),
+        }
+    }
+
+    fn decode<T: WireMessage>(self, bytes: &[u8]) -> Result<T, String> {
+        match self {
+            Encoding::Flexbuffers => Flexbuffers::decode(bytes),
+            Encoding::Cbor => Cbor::decode(bytes),
+            Encoding::Protobuf => Protobuf::decode(bytes),
+        }
+    }
+}
+
+/// Encodes outgoing messages in the configured encoding
+#[derive(Clone, Copy)]
+pub struct Encoder {
+    encoding: Encoding,
+    compress_above_bytes: Option<usize>,
+}
+
+impl Encoder {
+    pub fn new(config: &CodecConfig) -> Self {
+        Self {
+            encoding: config.encoding,
+            compress_above_bytes: config.compress_above_bytes,
+        }
+    }
+
+    pub fn encode<T: WireMessage>(&self, message: &T) -> Result<Vec<u8>, String> {
+        let mut tag = self.encoding.tag();
+        let mut body = self.encoding.encode(message)?;
+        if self.compress_above_bytes.is_some_and(|v| body.len() > v) {
+            tag |= COMPRESSED;
+            body = zstd::bulk::compress(&body, ZSTD_LEVEL).map_err(|err| err.to_string())?;
+        }
+
+        let mut payload = Vec::with_capacity(body.len() + 1);
+        payload.push(tag);
+        payload.extend(body);
+        Ok(payload)
+    }
+}
+
+/// Decodes a payload written by a peer speaking the given protocol version
+pub fn decode<T: WireMessage>(version: u16, payload: &[u8]) -> Result<T, String> {
+    if version < TAGGED_SINCE_VERSION {
+        return Flexbuffers::decode(payload);
+    }
+
+    let (&tag, body) = payload.split_first().ok_or("The payload is empty")?;
+    let encoding = Encoding::from_tag(tag & !COMPRESSED)
+        .ok_or_else(|| format!("Unknown encoding {}", tag & !COMPRESSED))?;
+    if tag & COMPRESSED == 0 {
+        return encoding.decode(body);
+    }
+    let body =
+        zstd::bulk::decompress(body, MAX_DECOMPRESSED_SIZE).map_err(|err| err.to_string())?;
+    encoding.decode(&body)
+}
+
+/// Mac addresses are sent as their 6 bytes in protobuf
+pub fn mac_from_bytes(bytes: &[u8]) -> Result<MacAddress, String> {
+    let bytes = <[u8; 6]>::try_from(bytes).map_err(|_| "A mac address has 6 bytes")?;
+    Ok(MacAddress::new(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::topics::host_occupation::{HostOccupationMessage, LoadAverage};
+
+    fn message() -> HostOccupationMessage {
+        HostOccupationMessage {
+            cpu_percentage: 42.5,
+            cpu_count: Some(8),
+            memory_used: Some(1024),
+            memory_total: Some(4096),
+            load_average: Some(LoadAverage {
+                one: 1.5,
+                five: 1.0,
+                fifteen: 0.5,
+            }),
+            smoothed: Some(Box::new(HostOccupationMessage {
+                cpu_percentage: 40.0,
+                ..Default::default()
+            })),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn decodes_every_encoding() {
+        for encoding in [Encoding::Flexbuffers, Encoding::Cbor, Encoding::Protobuf] {
+            for compress_above_bytes in [None, Some(0)] {
+                let encoder = Encoder::new(&CodecConfig {
+                    encoding,
+                    compress_above_bytes,
+                });
+                let payload = encoder.encode(&message()).unwrap();
+                assert_eq!(payload[0] & COMPRESSED != 0, compress_above_bytes.is_some());
+
+                let decoded: HostOccupationMessage =
+                    decode(TAGGED_SINCE_VERSION, &payload).unwrap();
+                assert_eq!(decoded.cpu_percentage, 42.5);
+                assert_eq!(decoded.memory_total, Some(4096));
+                assert_eq!(decoded.swap_total, None);
+                assert_eq!(decoded.load_average.unwrap().five, 1.0);
+                assert_eq!(decoded.smoothed.unwrap().cpu_percentage, 40.0);
+                assert!(decoded.peak.is_none());
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_encodings() {
+        let payload = Encoder::new(&CodecConfig::default())
+            .encode(&message())
+            .unwrap();
+        assert!(decode::<HostOccupationMessage>(TAGGED_SINCE_VERSION, &payload).is_ok());
+
+        let mut unknown = payload;
+        unknown[0] = 0x7f;
+        assert!(decode::<HostOccupationMessage>(TAGGED_SINCE_VERSION, &unknown).is_err());
+    }
+}
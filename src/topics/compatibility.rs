@@ -0,0 +1,124 @@
+//! Decodes messages recorded from older versions, so rolling upgrades keep working. Never
+//! change a fixture, add a new one for every version of a message instead. The v0 fixtures
+//! were recorded with the first release which sent the message, the baseline for host info and
+//! cpu only occupation and the last release before versioning for the others.
+
+use std::net::Ipv4Addr;
+
+use super::{
+    codec::decode, host_info::HostInfoMessage, host_occupation::HostOccupationMessage,
+    host_shutdown::HostShutdownMessage, leader_election::LeaderHeartbeatMessage,
+    wake_relay::WakeRelayMessage,
+};
+
+/// What the fixtures of host info advertise
+const V1_CAPABILITIES: [&str; 5] = [
+    "extended-occupation",
+    "scale-down",
+    "wake-relay",
+    "leader-election",
+    "token-rotation",
+];
+
+#[test]
+fn decodes_host_info() {
+    let v0: HostInfoMessage = decode(0, include_bytes!("fixtures/host_info_v0.bin")).unwrap();
+    assert_eq!(v0.mac_address.to_string(), "AA:BB:CC:DD:EE:FF");
+    assert_eq!(v0.name, "box");
+    assert!(v0.capabilities.is_empty());
+
+    let v1: HostInfoMessage = decode(1, include_bytes!("fixtures/host_info_v1.bin")).unwrap();
+    assert_eq!(v1.name, "box");
+    assert_eq!(v1.capabilities, V1_CAPABILITIES);
+
+    let v2: HostInfoMessage = decode(2, include_bytes!("fixtures/host_info_v2_cbor.bin")).unwrap();
+    assert_eq!(v2.mac_address.to_string(), "AA:BB:CC:DD:EE:FF");
+    assert_eq!(v2.capabilities, V1_CAPABILITIES);
+}
+
+#[test]
+fn decodes_host_info_from_newer_peers() {
+    let future: HostInfoMessage =
+        decode(1, include_bytes!("fixtures/host_info_future.bin")).unwrap();
+    assert_eq!(future.name, "box");
+    assert_eq!(future.capabilities, ["wake-relay", "teleportation"]);
+}
+
+#[test]
+fn decodes_host_occupation() {
+    let cpu_only: HostOccupationMessage = decode(
+        0,
+        include_bytes!("fixtures/host_occupation_cpu_only_v0.bin"),
+    )
+    .unwrap();
+    assert_eq!(cpu_only.cpu_percentage, 42.5);
+    assert_eq!(cpu_only.cpu_count, None);
+    assert!(cpu_only.load_average.is_none());
+
+    for (version, fixture) in [
+        (0, &include_bytes!("fixtures/host_occupation_v0.bin")[..]),
+        (
+            2,
+            include_bytes!("fixtures/host_occupation_v2_protobuf_zstd.bin"),
+        ),
+    ] {
+        let v: HostOccupationMessage = decode(version, fixture).unwrap();
+        assert_eq!(v.cpu_percentage, 42.5);
+        assert_eq!(v.cpu_count, Some(8));
+        assert_eq!((v.memory_used, v.memory_total), (Some(1024), Some(4096)));
+        assert_eq!((v.swap_used, v.swap_total), (Some(0), Some(2048)));
+        assert_eq!(v.load_average.unwrap().one, 1.5);
+        assert_eq!(v.disk_written_bytes_per_second, Some(200));
+        assert_eq!(v.network_transmitted_bytes_per_second, Some(400));
+    }
+}
+
+#[test]
+fn decodes_host_shutdown() {
+    let v0: HostShutdownMessage =
+        decode(0, include_bytes!("fixtures/host_shutdown_v0.bin")).unwrap();
+    assert_eq!(
+        v0.target_peer_id,
+        "12D3KooWQJJFTYoprvLhVNMcyurN1ZrttXMofhCCWjoE1oA16sSE"
+    );
+}
+
+#[test]
+fn decodes_leader_heartbeat() {
+    let v0: LeaderHeartbeatMessage =
+        decode(0, include_bytes!("fixtures/leader_heartbeat_v0.bin")).unwrap();
+    assert_eq!(v0.term, 7);
+}
+
+#[test]
+fn decodes_wake_relay() {
+    for (version, fixture) in [
+        (0, &include_bytes!("fixtures/wake_relay_request_v0.bin")[..]),
+        (
+            2,
+            include_bytes!("fixtures/wake_relay_request_v2_protobuf.bin"),
+        ),
+    ] {
+        let WakeRelayMessage::Request {
+            request_id,
+            mac_address,
+            address,
+            port,
+            secure_on_password,
+            relay,
+        } = decode(version, fixture).unwrap()
+        else {
+            panic!("expected a request");
+        };
+        assert_eq!(request_id, 99);
+        assert_eq!(mac_address.to_string(), "AA:BB:CC:DD:EE:FF");
+        assert_eq!(address, Ipv4Addr::new(192, 0, 2, 255));
+        assert_eq!(port, 9);
+        assert_eq!(secure_on_password, Some([1, 2, 3, 4, 5, 6]));
+        assert_eq!(relay.as_deref(), Some("relay-box"));
+    }
+
+    let ack: WakeRelayMessage =
+        decode(0, include_bytes!("fixtures/wake_relay_ack_v0.bin")).unwrap();
+    assert!(matches!(ack, WakeRelayMessage::Ack { request_id: 99, name } if name == "relay-box"));
+}
@@ -0,0 +1,464 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use chrono::{DateTime, Local};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use libp2p::{gossipsub::TopicHash, PeerId};
+use log::info;
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::config::{TokenKey, Tokens};
+
+use super::PROTOCOL_VERSION;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// How far the timestamp of a message may be off from our clock
+pub const TIMESTAMP_WINDOW: Duration = Duration::from_secs(30);
+
+const KEY_SALT: &[u8] = b"dyn-wol";
+const KEY_INFO: &[u8] = b"dyn-wol message authentication";
+
+/// Wraps the payload of every message on every topic
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+    /// The protocol version of the sender, envelopes from before versioning have none
+    #[serde(default)]
+    version: u16,
+    /// The id of the token key the mac was calculated with
+    key_id: String,
+    sender: Vec<u8>,
+    timestamp_millis: u64,
+    nonce: u64,
+    payload: Vec<u8>,
+    mac: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum EnvelopeError {
+    Malformed,
+    /// The envelope names another sender than the gossipsub message
+    SenderMismatch,
+    UnknownKey,
+    ExpiredKey,
+    InvalidMac,
+    OutsideWindow,
+    Replayed,
+}
+
+impl Display for EnvelopeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EnvelopeError::Malformed => write!(f, "the envelope is malformed"),
+            EnvelopeError::SenderMismatch => {
+                write!(f, "the envelope was not sent by the author of the message")
+            }
+            EnvelopeError::UnknownKey => write!(f, "the envelope was signed with an unknown key"),
+            EnvelopeError::ExpiredKey => write!(f, "the envelope was signed with an expired key"),
+            EnvelopeError::InvalidMac => write!(f, "the mac does not match the shared token"),
+            EnvelopeError::OutsideWindow => {
+                write!(f, "the timestamp is outside of the accepted window")
+            }
+            EnvelopeError::Replayed => write!(f, "the message has been seen before"),
+        }
+    }
+}
+
+/// The authenticated content of an envelope
+#[derive(Debug, PartialEq, Eq)]
+pub struct Opened {
+    pub sender: PeerId,
+    pub version: u16,
+    pub payload: Vec<u8>,
+}
+
+/// Seals outgoing messages with a key derived from the first token and opens incoming messages
+/// sealed with any of the tokens. Clones share their keys, so a reloaded token applies to all.
+#[derive(Clone)]
+pub struct Authenticator {
+    keys: Arc<RwLock<Vec<Key>>>,
+    local_peer_id: PeerId,
+    /// Nonces of messages within the timestamp window, with their timestamps
+    seen_nonces: Arc<Mutex<HashMap<(PeerId, u64), u64>>>,
+    /// The key id each peer used last, to log when a peer moves to another key
+    peer_keys: Arc<Mutex<HashMap<PeerId, String>>>,
+}
+
+#[derive(Clone)]
+struct Key {
+    token: TokenKey,
+    key: [u8; 32],
+}
+
+impl Authenticator {
+    pub fn new(tokens: &Tokens, local_peer_id: PeerId) -> Self {
+        Self {
+            keys: Arc::new(RwLock::new(derive_keys(tokens))),
+            local_peer_id,
+            seen_nonces: Arc::new(Mutex::new(HashMap::new())),
+            peer_keys: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Replaces the keys with the ones of a reloaded config
+    pub fn set_tokens(&self, tokens: &Tokens) {
+        let mut keys = self.keys.write().unwrap_or_else(|err| err.into_inner());
+        if keys.iter().map(|v| &v.token).eq(tokens.0.iter()) {
+            return;
+        }
+        *keys = derive_keys(tokens);
+        if let Some(signing) = tokens.signing() {
+            info!("Signing our messages with key {} from now on", signing.id);
+        }
+    }
+
+    /// Wraps the payload into an authenticated envelope
+    pub fn seal(&self, topic: &TopicHash, payload: Vec<u8>) -> Result<Vec<u8>, String> {
+        self.seal_at(topic, payload, now_millis())
+    }
+
+    pub(super) fn seal_at(
+        &self,
+        topic: &TopicHash,
+        payload: Vec<u8>,
+        timestamp_millis: u64,
+    ) -> Result<Vec<u8>, String> {
+        let keys = self.keys.read().unwrap_or_else(|err| err.into_inner());
+        let key = keys.first().ok_or("There is no token to sign with")?;
+        let sender = self.local_peer_id.to_bytes();
+        let nonce = OsRng.next_u64();
+        let mac = mac(key, topic, &sender, timestamp_millis, nonce, &payload)
+            .finalize()
+            .into_bytes()
+            .to_vec();
+
+        let envelope = Envelope {
+            version: PROTOCOL_VERSION,
+            key_id: key.token.id.clone(),
+            sender,
+            timestamp_millis,
+            nonce,
+            payload,
+            mac,
+        };
+        let mut s = flexbuffers::FlexbufferSerializer::new();
+        envelope.serialize(&mut s).map_err(|err| err.to_string())?;
+        Ok(s.view().into())
+    }
+
+    /// Verifies the envelope and returns the authenticated sender, version and payload. The
+    /// source is the author of the gossipsub message, which has to match the sender in the
+    /// envelope.
+    pub fn open(
+        &self,
+        topic: &TopicHash,
+        data: &[u8],
+        source: Option<PeerId>,
+    ) -> Result<Opened, EnvelopeError> {
+        self.open_at(topic, data, source, now_millis())
+    }
+
+    fn open_at(
+        &self,
+        topic: &TopicHash,
+        data: &[u8],
+        source: Option<PeerId>,
+        now_millis: u64,
+    ) -> Result<Opened, EnvelopeError> {
+        let reader = flexbuffers::Reader::get_root(data).map_err(|_| EnvelopeError::Malformed)?;
+        let envelope = Envelope::deserialize(reader).map_err(|_| EnvelopeError::Malformed)?;
+        let sender = PeerId::from_bytes(&envelope.sender).map_err(|_| EnvelopeError::Malformed)?;
+        if source.is_some_and(|v| v != sender) {
+            return Err(EnvelopeError::SenderMismatch);
+        }
+
+        let key = self
+            .keys
+            .read()
+            .unwrap_or_else(|err| err.into_inner())
+            .iter()
+            .find(|v| v.token.id == envelope.key_id)
+            .cloned()
+            .ok_or(EnvelopeError::UnknownKey)?;
+        let today = DateTime::from_timestamp_millis(now_millis as i64)
+            .map(|v| v.with_timezone(&Local).date_naive());
+        if today.is_some_and(|today| key.token.is_expired(today)) {
+            return Err(EnvelopeError::ExpiredKey);
+        }
+
+        mac(
+            &key,
+            topic,
+            &envelope.sender,
+            envelope.timestamp_millis,
+            envelope.nonce,
+            &envelope.payload,
+        )
+        .verify_slice(&envelope.mac)
+        .map_err(|_| EnvelopeError::InvalidMac)?;
+
+        let window = TIMESTAMP_WINDOW.as_millis() as u64;
+        if envelope.timestamp_millis.abs_diff(now_millis) > window {
+            return Err(EnvelopeError::OutsideWindow);
+        }
+
+        let mut seen_nonces = self
+            .seen_nonces
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        // anything older is rejected by the window anyway
+        seen_nonces.retain(|_, timestamp| timestamp.abs_diff(now_millis) <= window);
+        if seen_nonces
+            .insert((sender, envelope.nonce), envelope.timestamp_millis)
+            .is_some()
+        {
+            return Err(EnvelopeError::Replayed);
+        }
+        drop(seen_nonces);
+
+        let mut peer_keys = self.peer_keys.lock().unwrap_or_else(|err| err.into_inner());
+        if peer_keys.get(&sender) != Some(&key.token.id) {
+            info!("Peer {sender} signs its messages with key {}", key.token.id);
+            peer_keys.insert(sender, key.token.id.clone());
+        }
+
+        Ok(Opened {
+            sender,
+            version: envelope.version,
+            payload: envelope.payload,
+        })
+    }
+}
+
+/// The version is left out, so peers from before versioning can still verify our messages.
+/// Gossipsub signs every message, so the version can not be changed on the way either.
+fn mac(
+    key: &Key,
+    topic: &TopicHash,
+    sender: &[u8],
+    timestamp_millis: u64,
+    nonce: u64,
+    payload: &[u8],
+) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(&key.key).expect("hmac accepts keys of any length");
+    // length prefixes keep the variable sized parts from being shifted into each other
+    for part in [key.token.id.as_bytes(), topic.as_str().as_bytes(), sender] {
+        mac.update(&(part.len() as u64).to_le_bytes());
+        mac.update(part);
+    }
+    mac.update(&timestamp_millis.to_le_bytes());
+    mac.update(&nonce.to_le_bytes());
+    mac.update(&(payload.len() as u64).to_le_bytes());
+    mac.update(payload);
+    mac
+}
+
+/// Pretends the envelope was sealed by a peer speaking another version, which the mac does not
+/// cover
+#[cfg(test)]
+pub(super) fn with_version(data: &[u8], version: u16) -> Vec<u8> {
+    let reader = flexbuffers::Reader::get_root(data).unwrap();
+    let mut envelope = Envelope::deserialize(reader).unwrap();
+    envelope.version = version;
+    let mut s = flexbuffers::FlexbufferSerializer::new();
+    envelope.serialize(&mut s).unwrap();
+    s.view().into()
+}
+
+fn derive_keys(tokens: &Tokens) -> Vec<Key> {
+    tokens
+        .0
+        .iter()
+        .map(|token| {
+            let mut key = [0u8; 32];
+            Hkdf::<Sha256>::new(Some(KEY_SALT), token.secret.as_bytes())
+                .expand(KEY_INFO, &mut key)
+                .expect("32 bytes are a valid hkdf sha256 output length");
+            Key {
+                token: token.clone(),
+                key,
+            }
+        })
+        .collect()
+}
+
+pub(super) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|v| v.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::topics::{
+        codec,
+        host_info::HostInfoMessage,
+        testing::{authenticator, tokens, TOKEN},
+    };
+    use chrono::NaiveDate;
+
+    const NOW: u64 = 1_700_000_000_000;
+
+    fn topic() -> TopicHash {
+        TopicHash::from_raw("dyn-wol-host-info")
+    }
+
+    #[test]
+    fn opens_sealed_message() {
+        let sender = authenticator("key", TOKEN, None);
+        let receiver = authenticator("key", TOKEN, None);
+
+        let sealed = sender.seal_at(&topic(), b"payload".to_vec(), NOW).unwrap();
+        let opened = receiver
+            .open_at(&topic(), &sealed, Some(sender.local_peer_id), NOW + 1000)
+            .unwrap();
+        assert_eq!(opened.sender, sender.local_peer_id);
+        assert_eq!(opened.version, PROTOCOL_VERSION);
+        assert_eq!(opened.payload, b"payload");
+    }
+
+    #[test]
+    fn rejects_other_token() {
+        let sender = authenticator("key", "another-token-which-is-also-long-enough", None);
+        let receiver = authenticator("key", TOKEN, None);
+
+        let sealed = sender.seal_at(&topic(), b"payload".to_vec(), NOW).unwrap();
+        assert_eq!(
+            receiver.open_at(&topic(), &sealed, None, NOW),
+            Err(EnvelopeError::InvalidMac)
+        );
+    }
+
+    #[test]
+    fn rejects_other_topic_and_sender() {
+        let sender = authenticator("key", TOKEN, None);
+        let receiver = authenticator("key", TOKEN, None);
+
+        let sealed = sender.seal_at(&topic(), b"payload".to_vec(), NOW).unwrap();
+        assert_eq!(
+            receiver.open_at(
+                &TopicHash::from_raw("dyn-wol-host-shutdown"),
+                &sealed,
+                None,
+                NOW
+            ),
+            Err(EnvelopeError::InvalidMac)
+        );
+        assert_eq!(
+            receiver.open_at(&topic(), &sealed, Some(PeerId::random()), NOW),
+            Err(EnvelopeError::SenderMismatch)
+        );
+    }
+
+    #[test]
+    fn rejects_tampered_payload() {
+        let sender = authenticator("key", TOKEN, None);
+        let receiver = authenticator("key", TOKEN, None);
+
+        let sealed = sender.seal_at(&topic(), b"payload".to_vec(), NOW).unwrap();
+        let mut envelope =
+            Envelope::deserialize(flexbuffers::Reader::get_root(sealed.as_slice()).unwrap())
+                .unwrap();
+        envelope.payload[0] = b'P';
+        let mut s = flexbuffers::FlexbufferSerializer::new();
+        envelope.serialize(&mut s).unwrap();
+        let sealed = s.view().to_vec();
+
+        assert_eq!(
+            receiver.open_at(&topic(), &sealed, None, NOW),
+            Err(EnvelopeError::InvalidMac)
+        );
+    }
+
+    #[test]
+    fn rejects_replays_and_old_messages() {
+        let sender = authenticator("key", TOKEN, None);
+        let receiver = authenticator("key", TOKEN, None);
+
+        let sealed = sender.seal_at(&topic(), b"payload".to_vec(), NOW).unwrap();
+        assert!(receiver.open_at(&topic(), &sealed, None, NOW).is_ok());
+        assert_eq!(
+            receiver.open_at(&topic(), &sealed, None, NOW),
+            Err(EnvelopeError::Replayed)
+        );
+
+        let window = TIMESTAMP_WINDOW.as_millis() as u64;
+        assert_eq!(
+            receiver.open_at(&topic(), &sealed, None, NOW + window + 1),
+            Err(EnvelopeError::OutsideWindow)
+        );
+        let sealed = sender.seal_at(&topic(), b"payload".to_vec(), NOW).unwrap();
+        assert_eq!(
+            receiver.open_at(&topic(), &sealed, None, NOW - window - 1),
+            Err(EnvelopeError::OutsideWindow)
+        );
+    }
+
+    #[test]
+    fn accepts_any_unexpired_key() {
+        let old = ("old", "the-old-token-which-is-long-enough", None);
+        let new = ("new", TOKEN, None);
+        let rotated = Authenticator::new(&tokens(&[new, old]), PeerId::random());
+        let not_rotated = Authenticator::new(&tokens(&[old]), PeerId::random());
+
+        let sealed = not_rotated
+            .seal_at(&topic(), b"payload".to_vec(), NOW)
+            .unwrap();
+        assert!(rotated.open_at(&topic(), &sealed, None, NOW).is_ok());
+        let sealed = rotated.seal_at(&topic(), b"payload".to_vec(), NOW).unwrap();
+        assert_eq!(
+            not_rotated.open_at(&topic(), &sealed, None, NOW),
+            Err(EnvelopeError::UnknownKey)
+        );
+
+        let expired = NaiveDate::from_ymd_opt(2023, 1, 1);
+        let receiver =
+            Authenticator::new(&tokens(&[new, ("old", old.1, expired)]), PeerId::random());
+        let sealed = not_rotated
+            .seal_at(&topic(), b"payload".to_vec(), NOW)
+            .unwrap();
+        assert_eq!(
+            receiver.open_at(&topic(), &sealed, None, NOW),
+            Err(EnvelopeError::ExpiredKey)
+        );
+    }
+
+    #[test]
+    fn reloaded_tokens_apply_to_every_clone() {
+        let old = ("old", "the-old-token-which-is-long-enough", None);
+        let new = ("new", TOKEN, None);
+        let sender = Authenticator::new(&tokens(&[new]), PeerId::random());
+        let receiver = Authenticator::new(&tokens(&[old]), PeerId::random());
+        let validator = receiver.clone();
+
+        let sealed = sender.seal_at(&topic(), b"payload".to_vec(), NOW).unwrap();
+        assert_eq!(
+            validator.open_at(&topic(), &sealed, None, NOW),
+            Err(EnvelopeError::UnknownKey)
+        );
+        receiver.set_tokens(&tokens(&[new, old]));
+        assert!(validator.open_at(&topic(), &sealed, None, NOW).is_ok());
+    }
+
+    #[test]
+    fn opens_envelopes_from_before_versioning() {
+        let receiver = authenticator("key", TOKEN, None);
+        let fixture = include_bytes!("fixtures/envelope_v0.bin");
+
+        let opened = receiver.open_at(&topic(), fixture, None, NOW).unwrap();
+        let keypair = libp2p::identity::Keypair::ed25519_from_bytes([7u8; 32]).unwrap();
+        assert_eq!(opened.sender, keypair.public().to_peer_id());
+        assert_eq!(opened.version, 0);
+        let host_info: HostInfoMessage = codec::decode(0, &opened.payload).unwrap();
+        assert_eq!(host_info.name, "box");
+    }
+}
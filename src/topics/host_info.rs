@@ -1,170 +1,225 @@
-use std::{collections::HashMap, error::Error, sync::Arc, time::Duration};
-
-use crate::{config::AppConfig, MyBehaviour};
-use argon2::{
-    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
-    Argon2,
-};
-use kanal::AsyncSender;
-use libp2p::{
-    gossipsub::{self, TopicHash},
-    PeerId, Swarm,
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
 };
-use log::{error, warn};
+
+use crate::events::{ClusterEvent, EventBus, EvictionReason};
+use async_trait::async_trait;
+use libp2p::PeerId;
+use log::{error, info, warn};
 use mac_address::MacAddress;
-use serde::Serialize;
 use sysinfo::System;
-use tokio::{sync::RwLock, time};
+use tokio::sync::{watch, RwLock};
 
-use super::ExtractedTopicMessage;
+use super::{
+    codec::{mac_from_bytes, WireMessage},
+    ExtractedTopicMessage, PeerMap, Topic, BROADCAST_INTERVAL, PROTOCOL_VERSION,
+};
 
-type MapType = Arc<RwLock<HashMap<PeerId, OtherHost>>>;
+/// The features we support, advertised to the other peers. Names we do not know are kept, so
+/// newer peers can advertise features this version has never heard of.
+pub const CAPABILITIES: [&str; 7] = [
+    "extended-occupation",
+    "smoothed-occupation",
+    "scale-down",
+    "wake-relay",
+    "leader-election",
+    "token-rotation",
+    "shared-woken-hosts",
+];
+
+/// The name we advertise to the other peers, host entries name their relay by it
+pub fn local_name() -> Option<String> {
+    System::host_name()
+}
 
-pub struct HostInfo<'a> {
-    config: &'a AppConfig,
-    pub topic_hash: TopicHash,
-    map: MapType,
+#[derive(Clone)]
+pub struct HostInfo {
+    map: PeerMap<OtherHost>,
+    events: EventBus,
+    /// Notified whenever a peer joins or leaves
+    updated: Arc<watch::Sender<()>>,
 }
 
 pub struct OtherHost {
-    name: String,
+    pub name: String,
     pub mac_address: MacAddress,
+    pub last_seen: Instant,
+    pub protocol_version: u16,
+    pub capabilities: Vec<String>,
 }
 
 #[derive(serde::Deserialize, serde::Serialize)]
 pub struct HostInfoMessage {
-    token_hash: String,
-    mac_address: MacAddress,
+    pub(super) mac_address: MacAddress,
+    pub(super) name: String,
+    /// Peers from before capabilities were advertised send none
+    #[serde(default)]
+    pub(super) capabilities: Vec<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct HostInfoProto {
+    #[prost(bytes = "vec", tag = "1")]
+    mac_address: Vec<u8>,
+    #[prost(string, tag = "2")]
     name: String,
+    #[prost(string, repeated, tag = "3")]
+    capabilities: Vec<String>,
 }
 
-impl<'a> HostInfo<'a> {
-    pub fn register(
-        swarm: &mut Swarm<MyBehaviour>,
-        config: &'a AppConfig,
-        sender: &'a AsyncSender<(TopicHash, Vec<u8>)>,
-    ) -> Result<Self, Box<dyn Error>> {
-        let topic = gossipsub::IdentTopic::new("dyn-wol-host-info");
-        swarm.behaviour_mut().gossipsub.subscribe(&topic)?;
-
-        // preiodically broadcast our info
-        tokio::spawn({
-            let cloned_token = config.token.clone();
-            let topic_hash = topic.hash();
-            let cloned_sender = sender.clone();
-
-            async move {
-                let mut interval = time::interval(Duration::from_secs(3));
-                loop {
-                    interval.tick().await;
-                    Self::broadcast_host_info(
-                        cloned_token.clone(),
-                        cloned_sender.clone(),
-                        topic_hash.clone(),
-                    )
-                    .await;
-                }
-            }
-        });
+impl WireMessage for HostInfoMessage {
+    type Proto = HostInfoProto;
 
-        Ok(HostInfo {
-            config,
-            map: Arc::new(RwLock::new(HashMap::new())),
-            topic_hash: topic.hash(),
+    fn to_proto(&self) -> HostInfoProto {
+        HostInfoProto {
+            mac_address: self.mac_address.bytes().to_vec(),
+            name: self.name.clone(),
+            capabilities: self.capabilities.clone(),
+        }
+    }
+
+    fn from_proto(proto: HostInfoProto) -> Result<Self, String> {
+        Ok(HostInfoMessage {
+            mac_address: mac_from_bytes(&proto.mac_address)?,
+            name: proto.name,
+            capabilities: proto.capabilities,
         })
     }
+}
 
-    pub async fn handle_incoming_topic_message(
-        &self,
-        data: ExtractedTopicMessage<HostInfoMessage>,
-        token: &str,
-    ) {
-        let parsed_hash = match PasswordHash::new(&data.message.token_hash) {
-            Ok(v) => v,
-            Err(err) => {
-                error!("Could not parse hash: {err:#?}");
-                return;
-            }
-        };
+impl HostInfo {
+    pub fn new(events: &EventBus) -> Self {
+        HostInfo {
+            map: Arc::new(RwLock::new(HashMap::new())),
+            events: events.clone(),
+            updated: Arc::new(watch::Sender::new(())),
+        }
+    }
 
-        match Argon2::default().verify_password(token.as_bytes(), &parsed_hash) {
-            Ok(v) => v,
-            Err(err) => {
-                error!("Got invalid token in host info message, ignoring! {err:#?}");
-                return;
-            }
+    /// Changes whenever a peer joins or leaves
+    pub fn updates(&self) -> watch::Receiver<()> {
+        self.updated.subscribe()
+    }
+
+    /// Forgets about a peer, returns whether it was known
+    pub async fn evict_peer(&self, peer_id: &PeerId, reason: EvictionReason) -> bool {
+        let evicted = self.map.write().await.remove(peer_id).is_some();
+        if evicted {
+            self.updated.send_replace(());
+            self.events.emit(ClusterEvent::PeerEvicted {
+                peer_id: *peer_id,
+                reason,
+            });
         }
+        evicted
+    }
 
-        self.map.write().await.insert(
-            data.peer_id,
-            OtherHost {
-                mac_address: data.message.mac_address,
-                name: data.message.name,
-            },
-        );
+    /// Forgets about all peers which have not been seen for the given duration and returns them
+    pub async fn evict_stale(&self, max_age: Duration) -> Vec<PeerId> {
+        let stale = self
+            .map
+            .read()
+            .await
+            .iter()
+            .filter(|(_, v)| v.last_seen.elapsed() > max_age)
+            .map(|(peer_id, _)| *peer_id)
+            .collect::<Vec<_>>();
+
+        for peer_id in &stale {
+            self.evict_peer(peer_id, EvictionReason::Stale).await;
+        }
+        stale
     }
 
     pub async fn peer_id_is_registered(&self, id: &PeerId) -> bool {
         self.map.read().await.contains_key(id)
     }
 
-    pub fn get_map(&self) -> MapType {
+    pub fn get_map(&self) -> PeerMap<OtherHost> {
         self.map.clone()
     }
+}
+
+#[async_trait]
+impl Topic for HostInfo {
+    type Message = HostInfoMessage;
+
+    const NAME: &'static str = "dyn-wol-host-info";
+
+    fn broadcast_interval(&self) -> Option<Duration> {
+        Some(BROADCAST_INTERVAL)
+    }
 
-    async fn broadcast_host_info(
-        token: String,
-        sender: AsyncSender<(TopicHash, Vec<u8>)>,
-        topic_hash: TopicHash,
-    ) {
+    async fn produce(&self) -> Option<HostInfoMessage> {
         let mac_address = match mac_address::get_mac_address() {
             Ok(v) => match v {
                 Some(v) => v,
                 None => {
                     error!("Got no mac address");
-                    return;
+                    return None;
                 }
             },
             Err(err) => {
                 error!("Error reading mac address: {err:#?}");
-                return;
+                return None;
             }
         };
 
-        let name = match System::name() {
+        let name = match local_name() {
             Some(v) => v,
             None => {
-                error!("Could not get system name");
-                return;
+                error!("Could not get host name");
+                return None;
             }
         };
 
-        let salt = SaltString::generate(&mut OsRng);
-        let argon2 = Argon2::default();
-        let token_hash = match argon2.hash_password(token.into_bytes().as_ref(), &salt) {
-            Ok(v) => v,
-            Err(err) => {
-                error!("Serialize error: {err:#?}");
-                return;
-            }
-        }
-        .to_string();
-
-        let message = HostInfoMessage {
+        Some(HostInfoMessage {
             mac_address,
-            token_hash,
             name,
-        };
+            capabilities: CAPABILITIES.iter().map(|v| v.to_string()).collect(),
+        })
+    }
 
-        let mut s = flexbuffers::FlexbufferSerializer::new();
-        if let Err(err) = message.serialize(&mut s) {
-            error!("Serialize error: {err:#?}");
-            return;
-        }
+    async fn handle(&self, data: ExtractedTopicMessage<HostInfoMessage>) {
+        let previous = self.map.write().await.insert(
+            data.peer_id,
+            OtherHost {
+                mac_address: data.message.mac_address,
+                name: data.message.name.clone(),
+                last_seen: Instant::now(),
+                protocol_version: data.version,
+                capabilities: data.message.capabilities.clone(),
+            },
+        );
 
-        if let Err(err) = sender.send((topic_hash, s.view().into())).await {
-            error!("Failed to send {err:#?}");
-            return;
+        if previous.is_none() {
+            self.updated.send_replace(());
+            if data.version != PROTOCOL_VERSION {
+                info!(
+                    "Peer {} speaks protocol version {}, we speak {PROTOCOL_VERSION}",
+                    data.peer_id, data.version
+                );
+            }
+            let missing = CAPABILITIES
+                .iter()
+                .filter(|v| !data.message.capabilities.iter().any(|c| c == *v))
+                .copied()
+                .collect::<Vec<_>>();
+            if !missing.is_empty() {
+                warn!(
+                    "Peer {} does not support {}, mixed clusters may behave differently",
+                    data.peer_id,
+                    missing.join(", ")
+                );
+            }
+
+            self.events.emit(ClusterEvent::PeerDiscovered {
+                peer_id: data.peer_id,
+                name: data.message.name,
+                mac_address: data.message.mac_address,
+            });
         }
     }
 }
@@ -1,123 +1,292 @@
-use std::{collections::HashMap, error::Error, sync::Arc, time::Duration};
-
-use crate::MyBehaviour;
-use kanal::AsyncSender;
-use libp2p::{
-    gossipsub::{self, TopicHash},
-    PeerId, Swarm,
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
 };
-use log::{error, warn};
-use serde::Serialize;
-use sysinfo::System;
-use tokio::{sync::RwLock, time};
 
-use super::{host_info::HostInfo, ExtractedTopicMessage};
+use crate::{
+    config::{Metric, Reading},
+    local_metrics::LocalOccupation,
+};
+use async_trait::async_trait;
+use libp2p::PeerId;
+use log::warn;
+use tokio::sync::{watch, RwLock};
 
-type MapType = Arc<RwLock<HashMap<PeerId, OtherHostOccupation>>>;
+use super::{
+    codec::WireMessage, host_info::HostInfo, ExtractedTopicMessage, PeerMap, Topic,
+    BROADCAST_INTERVAL,
+};
 
-pub struct HostOccupation<'a> {
-    pub topic_hash: TopicHash,
-    map: MapType,
-    host_info: &'a HostInfo<'a>,
+#[derive(Clone)]
+pub struct HostOccupation {
+    map: PeerMap<OtherHostOccupation>,
+    host_info: HostInfo,
+    /// Our own occupation, which is broadcast
+    local: LocalOccupation,
+    /// Notified whenever a peer reports its occupation or is forgotten
+    updated: Arc<watch::Sender<()>>,
 }
 
 pub struct OtherHostOccupation {
-    cpu_percentage: f32,
+    pub occupation: HostOccupationMessage,
+    pub last_seen: Instant,
 }
 
-#[derive(serde::Deserialize, serde::Serialize)]
+/// Everything but the cpu percentage is optional, so peers which only know about the cpu
+/// percentage can still talk to us and the other way round
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Default)]
 pub struct HostOccupationMessage {
     pub cpu_percentage: f32,
+    #[serde(default)]
+    pub cpu_count: Option<u32>,
+    #[serde(default)]
+    pub memory_used: Option<u64>,
+    #[serde(default)]
+    pub memory_total: Option<u64>,
+    #[serde(default)]
+    pub swap_used: Option<u64>,
+    #[serde(default)]
+    pub swap_total: Option<u64>,
+    #[serde(default)]
+    pub load_average: Option<LoadAverage>,
+    #[serde(default)]
+    pub disk_read_bytes_per_second: Option<u64>,
+    #[serde(default)]
+    pub disk_written_bytes_per_second: Option<u64>,
+    #[serde(default)]
+    pub network_received_bytes_per_second: Option<u64>,
+    #[serde(default)]
+    pub network_transmitted_bytes_per_second: Option<u64>,
+    /// The samples smoothed over time, these have no smoothed or peak values themselves
+    #[serde(default)]
+    pub smoothed: Option<Box<HostOccupationMessage>>,
+    /// The peak percentile of the recent samples
+    #[serde(default)]
+    pub peak: Option<Box<HostOccupationMessage>>,
 }
 
-impl<'a> HostOccupation<'a> {
-    pub fn register(
-        swarm: &mut Swarm<MyBehaviour>,
-        sender: &'a AsyncSender<(TopicHash, Vec<u8>)>,
-        host_info: &'a HostInfo,
-    ) -> Result<Self, Box<dyn Error>> {
-        let topic = gossipsub::IdentTopic::new("dyn-wol-host-occupation");
-        swarm.behaviour_mut().gossipsub.subscribe(&topic)?;
-
-        // preiodically broadcast our occupation
-        tokio::spawn({
-            let topic_hash = topic.hash();
-            let cloned_sender = sender.clone();
-
-            async move {
-                let mut interval = time::interval(Duration::from_secs(3));
-                loop {
-                    interval.tick().await;
-                    Self::broadcast_host_occupation(cloned_sender.clone(), topic_hash.clone())
-                        .await;
-                }
-            }
-        });
-
-        Ok(HostOccupation {
-            host_info,
-            map: Arc::new(RwLock::new(HashMap::new())),
-            topic_hash: topic.hash(),
-        })
-    }
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Copy)]
+pub struct LoadAverage {
+    pub one: f64,
+    pub five: f64,
+    pub fifteen: f64,
+}
 
-    pub async fn handle_incoming_topic_message(
-        &self,
-        data: ExtractedTopicMessage<HostOccupationMessage>,
-    ) {
-        if !self.host_info.peer_id_is_registered(&data.peer_id).await {
-            warn!("Got occupation message from non registered peer!");
-            return;
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct HostOccupationProto {
+    #[prost(float, tag = "1")]
+    cpu_percentage: f32,
+    #[prost(uint32, optional, tag = "2")]
+    cpu_count: Option<u32>,
+    #[prost(uint64, optional, tag = "3")]
+    memory_used: Option<u64>,
+    #[prost(uint64, optional, tag = "4")]
+    memory_total: Option<u64>,
+    #[prost(uint64, optional, tag = "5")]
+    swap_used: Option<u64>,
+    #[prost(uint64, optional, tag = "6")]
+    swap_total: Option<u64>,
+    #[prost(message, optional, tag = "7")]
+    load_average: Option<LoadAverageProto>,
+    #[prost(uint64, optional, tag = "8")]
+    disk_read_bytes_per_second: Option<u64>,
+    #[prost(uint64, optional, tag = "9")]
+    disk_written_bytes_per_second: Option<u64>,
+    #[prost(uint64, optional, tag = "10")]
+    network_received_bytes_per_second: Option<u64>,
+    #[prost(uint64, optional, tag = "11")]
+    network_transmitted_bytes_per_second: Option<u64>,
+    #[prost(message, optional, boxed, tag = "12")]
+    smoothed: Option<Box<HostOccupationProto>>,
+    #[prost(message, optional, boxed, tag = "13")]
+    peak: Option<Box<HostOccupationProto>>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct LoadAverageProto {
+    #[prost(double, tag = "1")]
+    one: f64,
+    #[prost(double, tag = "2")]
+    five: f64,
+    #[prost(double, tag = "3")]
+    fifteen: f64,
+}
+
+impl WireMessage for HostOccupationMessage {
+    type Proto = HostOccupationProto;
+
+    fn to_proto(&self) -> HostOccupationProto {
+        HostOccupationProto {
+            cpu_percentage: self.cpu_percentage,
+            cpu_count: self.cpu_count,
+            memory_used: self.memory_used,
+            memory_total: self.memory_total,
+            swap_used: self.swap_used,
+            swap_total: self.swap_total,
+            load_average: self.load_average.map(|v| LoadAverageProto {
+                one: v.one,
+                five: v.five,
+                fifteen: v.fifteen,
+            }),
+            disk_read_bytes_per_second: self.disk_read_bytes_per_second,
+            disk_written_bytes_per_second: self.disk_written_bytes_per_second,
+            network_received_bytes_per_second: self.network_received_bytes_per_second,
+            network_transmitted_bytes_per_second: self.network_transmitted_bytes_per_second,
+            smoothed: self.smoothed.as_ref().map(|v| Box::new(v.to_proto())),
+            peak: self.peak.as_ref().map(|v| Box::new(v.to_proto())),
         }
+    }
 
-        self.map.write().await.insert(
-            data.peer_id,
-            OtherHostOccupation {
-                cpu_percentage: data.message.cpu_percentage,
-            },
-        );
+    fn from_proto(proto: HostOccupationProto) -> Result<Self, String> {
+        Ok(HostOccupationMessage {
+            cpu_percentage: proto.cpu_percentage,
+            cpu_count: proto.cpu_count,
+            memory_used: proto.memory_used,
+            memory_total: proto.memory_total,
+            swap_used: proto.swap_used,
+            swap_total: proto.swap_total,
+            load_average: proto.load_average.map(|v| LoadAverage {
+                one: v.one,
+                five: v.five,
+                fifteen: v.fifteen,
+            }),
+            disk_read_bytes_per_second: proto.disk_read_bytes_per_second,
+            disk_written_bytes_per_second: proto.disk_written_bytes_per_second,
+            network_received_bytes_per_second: proto.network_received_bytes_per_second,
+            network_transmitted_bytes_per_second: proto.network_transmitted_bytes_per_second,
+            smoothed: proto
+                .smoothed
+                .map(|v| Self::from_proto(*v).map(Box::new))
+                .transpose()?,
+            peak: proto
+                .peak
+                .map(|v| Self::from_proto(*v).map(Box::new))
+                .transpose()?,
+        })
     }
+}
 
-    async fn broadcast_host_occupation(
-        sender: AsyncSender<(TopicHash, Vec<u8>)>,
-        topic_hash: TopicHash,
-    ) {
-        let mut sys = System::new_all();
-        sys.refresh_all();
+impl HostOccupationMessage {
+    /// The values a rule looks at, the latest sample if the peer did not report the others
+    pub fn reading(&self, reading: Reading) -> &HostOccupationMessage {
+        let other = match reading {
+            Reading::Current => None,
+            Reading::Smoothed => self.smoothed.as_deref(),
+            Reading::Peak => self.peak.as_deref(),
+        };
+        other.unwrap_or(self)
+    }
 
-        let message = HostOccupationMessage {
-            cpu_percentage: sys.global_cpu_usage(),
+    /// Reads a single metric, None if the sending peer did not report it
+    pub fn metric(&self, metric: Metric) -> Option<f32> {
+        let percentage = |used: Option<u64>, total: Option<u64>| match (used, total) {
+            (Some(used), Some(total)) if total > 0 => Some(used as f32 / total as f32 * 100f32),
+            _ => None,
+        };
+        let per_core = |load: fn(&LoadAverage) -> f64| {
+            let cores = self.cpu_count.filter(|v| *v > 0)?;
+            Some((load(self.load_average.as_ref()?) / cores as f64) as f32)
         };
+        let sum = |a: Option<u64>, b: Option<u64>| Some((a? + b?) as f32);
 
-        let mut s = flexbuffers::FlexbufferSerializer::new();
-        if let Err(err) = message.serialize(&mut s) {
-            error!("Serialize error: {err:#?}");
-            return;
+        match metric {
+            Metric::CpuPercentage => Some(self.cpu_percentage),
+            Metric::MemoryPercentage => percentage(self.memory_used, self.memory_total),
+            Metric::SwapPercentage => percentage(self.swap_used, self.swap_total),
+            Metric::Load1PerCore => per_core(|v| v.one),
+            Metric::Load5PerCore => per_core(|v| v.five),
+            Metric::Load15PerCore => per_core(|v| v.fifteen),
+            Metric::DiskBytesPerSecond => sum(
+                self.disk_read_bytes_per_second,
+                self.disk_written_bytes_per_second,
+            ),
+            Metric::NetworkBytesPerSecond => sum(
+                self.network_received_bytes_per_second,
+                self.network_transmitted_bytes_per_second,
+            ),
         }
+    }
+}
 
-        if let Err(err) = sender.send((topic_hash, s.view().into())).await {
-            error!("Failed to send {err:#?}");
-            return;
+impl HostOccupation {
+    pub fn new(host_info: &HostInfo, local: &LocalOccupation) -> Self {
+        HostOccupation {
+            host_info: host_info.clone(),
+            map: Arc::new(RwLock::new(HashMap::new())),
+            local: local.clone(),
+            updated: Arc::new(watch::Sender::new(())),
         }
     }
 
-    pub fn get_map(&self) -> MapType {
-        self.map.clone()
+    /// Changes whenever a peer reports its occupation or is forgotten
+    pub fn updates(&self) -> watch::Receiver<()> {
+        self.updated.subscribe()
     }
 
-    pub async fn calculate_total_occupation(map: &MapType) -> HostOccupationMessage {
-        let mut sys = System::new_all();
-        sys.refresh_all();
+    pub async fn evict_peer(&self, peer_id: &PeerId) {
+        if self.map.write().await.remove(peer_id).is_some() {
+            self.updated.send_replace(());
+        }
+    }
 
-        let others = map.read().await;
-        let mut total_occupation = sys.global_cpu_usage();
+    pub async fn evict_stale(&self, max_age: Duration) {
+        let mut map = self.map.write().await;
+        let before = map.len();
+        map.retain(|_, v| v.last_seen.elapsed() <= max_age);
+        if map.len() != before {
+            self.updated.send_replace(());
+        }
+    }
+
+    pub fn get_map(&self) -> PeerMap<OtherHostOccupation> {
+        self.map.clone()
+    }
+
+    pub fn calculate_total_occupation(
+        others: &HashMap<PeerId, OtherHostOccupation>,
+        local: &HostOccupationMessage,
+    ) -> HostOccupationMessage {
+        let mut total_occupation = local.cpu_percentage;
 
         for (_, other_host_occupation) in others.iter() {
-            total_occupation += other_host_occupation.cpu_percentage;
+            total_occupation += other_host_occupation.occupation.cpu_percentage;
         }
 
         HostOccupationMessage {
             cpu_percentage: total_occupation / (others.len() as f32 + 1f32),
+            ..Default::default()
+        }
+    }
+}
+
+#[async_trait]
+impl Topic for HostOccupation {
+    type Message = HostOccupationMessage;
+
+    const NAME: &'static str = "dyn-wol-host-occupation";
+
+    fn broadcast_interval(&self) -> Option<Duration> {
+        Some(BROADCAST_INTERVAL)
+    }
+
+    async fn produce(&self) -> Option<HostOccupationMessage> {
+        self.local.borrow().clone()
+    }
+
+    async fn handle(&self, data: ExtractedTopicMessage<HostOccupationMessage>) {
+        if !self.host_info.peer_id_is_registered(&data.peer_id).await {
+            warn!("Got occupation message from non registered peer!");
+            return;
         }
+
+        self.map.write().await.insert(
+            data.peer_id,
+            OtherHostOccupation {
+                occupation: data.message,
+                last_seen: Instant::now(),
+            },
+        );
+        self.updated.send_replace(());
     }
 }
@@ -0,0 +1,123 @@
+use crate::{config::ScaleDownConfig, reload::LiveConfig};
+use async_trait::async_trait;
+use libp2p::PeerId;
+use log::{error, info, warn};
+use tokio::process::Command;
+
+use super::{codec::WireMessage, registry::Publisher, ExtractedTopicMessage, Topic};
+
+#[derive(Clone)]
+pub struct HostShutdown {
+    publisher: Publisher,
+    local_peer_id: PeerId,
+    config: LiveConfig,
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct HostShutdownMessage {
+    pub(super) target_peer_id: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct HostShutdownProto {
+    #[prost(string, tag = "1")]
+    target_peer_id: String,
+}
+
+impl WireMessage for HostShutdownMessage {
+    type Proto = HostShutdownProto;
+
+    fn to_proto(&self) -> HostShutdownProto {
+        HostShutdownProto {
+            target_peer_id: self.target_peer_id.clone(),
+        }
+    }
+
+    fn from_proto(proto: HostShutdownProto) -> Result<Self, String> {
+        Ok(HostShutdownMessage {
+            target_peer_id: proto.target_peer_id,
+        })
+    }
+}
+
+impl HostShutdown {
+    pub fn new(publisher: Publisher, local_peer_id: PeerId, config: &LiveConfig) -> Self {
+        HostShutdown {
+            publisher,
+            local_peer_id,
+            config: config.clone(),
+        }
+    }
+
+    /// Asks the peer with the given id to run its configured shutdown command
+    pub async fn request_shutdown(&self, peer_id: &PeerId) {
+        let message = HostShutdownMessage {
+            target_peer_id: peer_id.to_string(),
+        };
+        self.publisher.publish(&message).await;
+    }
+}
+
+/// The command to run when asked to shut down. Hosts which did not enable scale-down themselves
+/// are never shut down, whatever the leader thinks.
+fn shutdown_command(config: &ScaleDownConfig) -> Option<String> {
+    if !config.enabled {
+        warn!("Got asked to shut down but scale-down is not enabled, ignoring!");
+        return None;
+    }
+    if config.command.is_empty() {
+        warn!("Got asked to shut down but no shutdown command is configured, ignoring!");
+        return None;
+    }
+    Some(config.command.clone())
+}
+
+#[async_trait]
+impl Topic for HostShutdown {
+    type Message = HostShutdownMessage;
+
+    const NAME: &'static str = "dyn-wol-host-shutdown";
+
+    async fn handle(&self, data: ExtractedTopicMessage<HostShutdownMessage>) {
+        if data.message.target_peer_id != self.local_peer_id.to_string() {
+            return;
+        }
+
+        let Some(command) = shutdown_command(&self.config.borrow().scale_down) else {
+            return;
+        };
+
+        info!(
+            "Peer {} asked us to shut down, running: {command}",
+            data.peer_id
+        );
+        // the command may take a while, the other messages should not wait for it
+        tokio::spawn(async move {
+            match Command::new("sh").arg("-c").arg(&command).status().await {
+                Ok(status) if status.success() => {}
+                Ok(status) => error!("Shutdown command exited with {status}"),
+                Err(err) => error!("Could not run shutdown command: {err:#?}"),
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runs_the_command_only_when_scale_down_is_enabled() {
+        let mut config = ScaleDownConfig::default();
+        assert_eq!(shutdown_command(&config), None);
+
+        config.enabled = true;
+        assert_eq!(
+            shutdown_command(&config).as_deref(),
+            Some("systemctl suspend")
+        );
+
+        config.command = String::new();
+        assert_eq!(shutdown_command(&config), None);
+    }
+}
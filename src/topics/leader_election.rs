@@ -0,0 +1,215 @@
+use std::{
+    collections::HashSet,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    election::Election,
+    events::{ClusterEvent, EventBus},
+};
+use async_trait::async_trait;
+use libp2p::PeerId;
+use mac_address::MacAddress;
+use tokio::sync::RwLock;
+
+use super::{
+    codec::{mac_from_bytes, WireMessage},
+    ExtractedTopicMessage, Topic,
+};
+
+/// Makes sure only a single peer takes scaling decisions at any time
+#[derive(Clone)]
+pub struct LeaderElection {
+    local_peer_id: PeerId,
+    election: Arc<RwLock<Election>>,
+    events: EventBus,
+    heartbeat: Duration,
+    /// Hosts the leader woke on its own and did not suspend since, only those are ever
+    /// suspended. The leader sends them with its heartbeats, so the next leader knows them too.
+    woken: Arc<RwLock<HashSet<MacAddress>>>,
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct LeaderHeartbeatMessage {
+    pub(super) term: u64,
+    /// Leaders from before the woken hosts were shared send none
+    #[serde(default)]
+    pub(super) woken: Vec<MacAddress>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct LeaderHeartbeatProto {
+    #[prost(uint64, tag = "1")]
+    term: u64,
+    #[prost(bytes = "vec", repeated, tag = "2")]
+    woken: Vec<Vec<u8>>,
+}
+
+impl WireMessage for LeaderHeartbeatMessage {
+    type Proto = LeaderHeartbeatProto;
+
+    fn to_proto(&self) -> LeaderHeartbeatProto {
+        LeaderHeartbeatProto {
+            term: self.term,
+            woken: self.woken.iter().map(|v| v.bytes().to_vec()).collect(),
+        }
+    }
+
+    fn from_proto(proto: LeaderHeartbeatProto) -> Result<Self, String> {
+        Ok(LeaderHeartbeatMessage {
+            term: proto.term,
+            woken: proto
+                .woken
+                .iter()
+                .map(|v| mac_from_bytes(v))
+                .collect::<Result<_, _>>()?,
+        })
+    }
+}
+
+impl LeaderElection {
+    pub fn new(
+        local_peer_id: PeerId,
+        events: &EventBus,
+        heartbeat: Duration,
+        lease: Duration,
+    ) -> Self {
+        LeaderElection {
+            local_peer_id,
+            election: Arc::new(RwLock::new(Election::new(
+                local_peer_id,
+                lease,
+                Instant::now(),
+            ))),
+            events: events.clone(),
+            heartbeat,
+            woken: Arc::new(RwLock::new(HashSet::new())),
+        }
+    }
+
+    pub async fn woken_mac_addresses(&self) -> Vec<MacAddress> {
+        self.woken.read().await.iter().copied().collect()
+    }
+
+    /// Remembers that we woke the host while leading
+    pub async fn record_woken(&self, mac_address: MacAddress) {
+        self.woken.write().await.insert(mac_address);
+    }
+
+    /// Forgets that the host was woken once it was asked to shut down
+    pub async fn record_suspended(&self, mac_address: &MacAddress) {
+        self.woken.write().await.remove(mac_address);
+    }
+
+    /// Whether we currently hold the lease and may take scaling decisions
+    pub async fn is_leader(&self) -> bool {
+        self.election.read().await.is_leader(Instant::now())
+    }
+
+    /// The current leader and its term, if its lease is still valid
+    pub async fn leader(&self) -> Option<(PeerId, u64)> {
+        let election = self.election.read().await;
+        election
+            .leader(Instant::now())
+            .map(|leader| (leader, election.term()))
+    }
+}
+
+#[async_trait]
+impl Topic for LeaderElection {
+    type Message = LeaderHeartbeatMessage;
+
+    const NAME: &'static str = "dyn-wol-leader-election";
+
+    /// We claim or renew our lease with every heartbeat
+    fn broadcast_interval(&self) -> Option<Duration> {
+        Some(self.heartbeat)
+    }
+
+    async fn produce(&self) -> Option<LeaderHeartbeatMessage> {
+        let (term, became_leader) = {
+            let mut election = self.election.write().await;
+            let was_leader = election.leader(Instant::now()) == Some(self.local_peer_id);
+            let term = election.tick(Instant::now())?;
+            (term, !was_leader)
+        };
+        if became_leader {
+            self.events.emit(ClusterEvent::LeaderChanged {
+                peer_id: self.local_peer_id,
+                term,
+            });
+        }
+        Some(LeaderHeartbeatMessage {
+            term,
+            woken: self.woken_mac_addresses().await,
+        })
+    }
+
+    async fn handle(&self, data: ExtractedTopicMessage<LeaderHeartbeatMessage>) {
+        // the envelope authenticates the author, so heartbeats can not be forged for others
+        let leader = data.peer_id;
+        let (changed, accepted) = {
+            let mut election = self.election.write().await;
+            let now = Instant::now();
+            let changed = election.handle_heartbeat(data.message.term, leader, now);
+            (changed, election.leader(now) == Some(leader))
+        };
+        // the leader's view of the woken hosts is the one which counts
+        if accepted {
+            *self.woken.write().await = data.message.woken.into_iter().collect();
+        }
+        if changed {
+            self.events.emit(ClusterEvent::LeaderChanged {
+                peer_id: leader,
+                term: data.message.term,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::topics::PROTOCOL_VERSION;
+
+    fn heartbeat(
+        peer_id: PeerId,
+        term: u64,
+        woken: Vec<MacAddress>,
+    ) -> ExtractedTopicMessage<LeaderHeartbeatMessage> {
+        ExtractedTopicMessage {
+            peer_id,
+            version: PROTOCOL_VERSION,
+            message: LeaderHeartbeatMessage { term, woken },
+        }
+    }
+
+    #[tokio::test]
+    async fn takes_over_the_woken_hosts_of_the_leader() {
+        let election = LeaderElection::new(
+            PeerId::random(),
+            &EventBus::new(),
+            Duration::from_secs(1),
+            Duration::from_secs(5),
+        );
+        let leader = PeerId::random();
+        let mac = MacAddress::new([2, 0, 0, 0, 0, 1]);
+
+        election
+            .record_woken(MacAddress::new([2, 0, 0, 0, 0, 9]))
+            .await;
+        election.handle(heartbeat(leader, 3, vec![mac])).await;
+        assert_eq!(election.woken_mac_addresses().await, vec![mac]);
+
+        // a peer with an older term does not lead, so its view is ignored
+        election
+            .handle(heartbeat(PeerId::random(), 2, Vec::new()))
+            .await;
+        assert_eq!(election.woken_mac_addresses().await, vec![mac]);
+
+        // the leader suspended the host
+        election.handle(heartbeat(leader, 3, Vec::new())).await;
+        assert!(election.woken_mac_addresses().await.is_empty());
+    }
+}
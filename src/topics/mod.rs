@@ -1,51 +1,341 @@
+use async_trait::async_trait;
+use codec::WireMessage;
+use envelope::{Authenticator, EnvelopeError};
 use libp2p::{
-    gossipsub::{self, TopicHash},
+    gossipsub::{self, MessageAcceptance, TopicHash},
     PeerId,
 };
-use log::error;
+use log::{error, warn};
+
+use crate::metrics::METRICS;
 use serde::Deserialize;
+use tokio::sync::RwLock;
+
+use std::{collections::HashMap, sync::Arc, time::Duration};
 
+pub mod codec;
+#[cfg(test)]
+mod compatibility;
+pub mod envelope;
 pub mod host_info;
 pub mod host_occupation;
+pub mod host_shutdown;
+pub mod leader_election;
+pub mod registry;
+#[cfg(test)]
+mod testing;
+pub mod wake_relay;
+
+/// The version of the wire protocol we speak, sent in every envelope. Messages of every version
+/// are accepted, those of newer versions are decoded as far as we can and fields we do not know
+/// about are skipped.
+pub const PROTOCOL_VERSION: u16 = 2;
+
+/// How often each host broadcasts its own state
+pub const BROADCAST_INTERVAL: Duration = Duration::from_secs(3);
+
+/// The latest state every peer broadcast on a topic
+pub type PeerMap<T> = Arc<RwLock<HashMap<PeerId, T>>>;
+
+/// A gossipsub topic carrying messages of a single type. Topics are registered with the
+/// `TopicRegistry`, which subscribes them, runs their broadcasts and hands them their messages.
+#[async_trait]
+pub trait Topic: Clone + Send + Sync + 'static {
+    type Message: WireMessage;
+
+    /// The name the topic is subscribed under
+    const NAME: &'static str;
+
+    /// How often `produce` is asked for a message to broadcast, None for topics which only
+    /// publish on demand
+    fn broadcast_interval(&self) -> Option<Duration> {
+        None
+    }
+
+    /// The next message to broadcast, None to skip this round
+    async fn produce(&self) -> Option<Self::Message> {
+        None
+    }
+
+    /// Runs in its own task for every message, so a slow handler does not hold up the others.
+    /// Messages on the same topic may therefore be handled concurrently.
+    async fn handle(&self, data: ExtractedTopicMessage<Self::Message>);
+}
 
 pub struct ExtractedTopicMessage<T: for<'a> Deserialize<'a>> {
     peer_id: PeerId,
+    /// The protocol version of the author
+    version: u16,
     message: T,
 }
 
-pub fn extract_topic_message<T: for<'a> Deserialize<'a>>(
-    event: &gossipsub::Event,
-    valid_topic_hash: &TopicHash,
-) -> Option<ExtractedTopicMessage<T>> {
-    match event {
-        gossipsub::Event::Message {
-            propagation_source: peer_id,
-            message_id: _,
-            message,
-        } => match message.topic.eq(valid_topic_hash) {
-            true => {
-                let raw: &[u8] = &message.data;
-                let r = match flexbuffers::Reader::get_root(raw) {
-                    Ok(v) => v,
-                    Err(err) => {
-                        error!("Could not extract message: {err}");
-                        return None;
-                    }
-                };
-                let extracted = match T::deserialize(r) {
-                    Ok(v) => v,
-                    Err(err) => {
-                        error!("Could not extract message: {err}");
-                        return None;
-                    }
-                };
-                Some(ExtractedTopicMessage {
-                    message: extracted,
-                    peer_id: *peer_id,
-                })
+/// A message which passed validation, its envelope is already opened
+pub struct ValidatedMessage {
+    topic: TopicHash,
+    peer_id: PeerId,
+    /// The protocol version of the author
+    version: u16,
+    payload: Vec<u8>,
+}
+
+type SchemaCheck = fn(u16, &[u8]) -> bool;
+
+/// Decides whether gossipsub may forward a message, before any handler sees it
+#[derive(Clone)]
+pub struct MessageValidator {
+    authenticator: Authenticator,
+    schemas: HashMap<TopicHash, SchemaCheck>,
+    /// Only messages authored by these peers are accepted, unless it is empty
+    allowed_peers: Vec<PeerId>,
+}
+
+impl MessageValidator {
+    pub fn new(authenticator: Authenticator, allowed_peers: &[PeerId]) -> Self {
+        Self {
+            authenticator,
+            schemas: HashMap::new(),
+            allowed_peers: allowed_peers.to_vec(),
+        }
+    }
+
+    /// Accepts messages on the topic if their payload decodes into `T`
+    pub fn register<T: WireMessage>(&mut self, topic_hash: &TopicHash) {
+        self.schemas.insert(topic_hash.clone(), |version, payload| {
+            codec::decode::<T>(version, payload).is_ok()
+        });
+    }
+
+    pub fn topic_hashes(&self) -> impl Iterator<Item = &TopicHash> {
+        self.schemas.keys()
+    }
+
+    /// Authenticates the message and checks its payload against the schema of its topic.
+    /// Messages which are merely late or duplicated, or were written by a peer speaking another
+    /// version, are ignored instead of rejected, so the peer forwarding them is not penalised.
+    pub fn validate(
+        &self,
+        message: &gossipsub::Message,
+    ) -> Result<ValidatedMessage, MessageAcceptance> {
+        let Some(schema_check) = self.schemas.get(&message.topic) else {
+            return Err(MessageAcceptance::Ignore);
+        };
+
+        let opened = match self
+            .authenticator
+            .open(&message.topic, &message.data, message.source)
+        {
+            Ok(v) => v,
+            // a peer signing with an expired or unknown key is on the other side of a rotation,
+            // it is not hostile
+            Err(
+                err @ (EnvelopeError::OutsideWindow
+                | EnvelopeError::Replayed
+                | EnvelopeError::UnknownKey
+                | EnvelopeError::ExpiredKey),
+            ) => {
+                warn!("Ignoring message on {}: {err}", message.topic);
+                return Err(MessageAcceptance::Ignore);
+            }
+            Err(err) => {
+                error!("Rejected message on {}: {err}", message.topic);
+                METRICS.messages_rejected.inc();
+                return Err(MessageAcceptance::Reject);
             }
-            false => None,
-        },
-        _ => None,
+        };
+
+        // whoever forwarded it may not know the peer is not allowed here, so it is not punished
+        let peer_id = opened.sender;
+        if !self.allowed_peers.is_empty() && !self.allowed_peers.contains(&peer_id) {
+            warn!(
+                "Ignoring message on {} from unknown peer {peer_id}",
+                message.topic
+            );
+            return Err(MessageAcceptance::Ignore);
+        }
+
+        if !schema_check(opened.version, &opened.payload) {
+            // newer peers may send messages we do not know about yet
+            if opened.version > PROTOCOL_VERSION {
+                warn!(
+                    "Ignoring message on {} of newer protocol version {} from {peer_id}",
+                    message.topic, opened.version
+                );
+                return Err(MessageAcceptance::Ignore);
+            }
+            error!(
+                "Rejected message on {}: the payload does not decode",
+                message.topic
+            );
+            METRICS.messages_rejected.inc();
+            METRICS.decode_failures.inc();
+            return Err(MessageAcceptance::Reject);
+        }
+
+        Ok(ValidatedMessage {
+            topic: message.topic.clone(),
+            peer_id,
+            version: opened.version,
+            payload: opened.payload,
+        })
+    }
+}
+
+/// Decodes a validated message for the handler of its topic
+fn extract_topic_message<T: WireMessage>(
+    message: &ValidatedMessage,
+) -> Option<ExtractedTopicMessage<T>> {
+    match codec::decode::<T>(message.version, &message.payload) {
+        Ok(v) => Some(ExtractedTopicMessage {
+            peer_id: message.peer_id,
+            version: message.version,
+            message: v,
+        }),
+        Err(err) => {
+            error!("Could not extract message: {err}");
+            METRICS.decode_failures.inc();
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use super::*;
+    use crate::config::CodecConfig;
+    use codec::Encoder;
+    use envelope::{with_version, TIMESTAMP_WINDOW};
+    use host_shutdown::HostShutdownMessage;
+    use testing::{authenticator, now_millis, TOKEN};
+
+    fn topic() -> TopicHash {
+        gossipsub::IdentTopic::new("dyn-wol-host-shutdown").hash()
+    }
+
+    fn registered(authenticator: Authenticator, allowed_peers: &[PeerId]) -> MessageValidator {
+        let mut validator = MessageValidator::new(authenticator, allowed_peers);
+        validator.register::<HostShutdownMessage>(&topic());
+        validator
+    }
+
+    fn payload() -> Vec<u8> {
+        Encoder::new(&CodecConfig::default())
+            .encode(&HostShutdownMessage {
+                target_peer_id: "peer".into(),
+            })
+            .unwrap()
+    }
+
+    fn message(data: Vec<u8>, source: Option<PeerId>) -> gossipsub::Message {
+        gossipsub::Message {
+            source,
+            data,
+            sequence_number: None,
+            topic: topic(),
+        }
+    }
+
+    fn outcome(validator: &MessageValidator, message: &gossipsub::Message) -> String {
+        match validator.validate(message) {
+            Ok(_) => "Accept".into(),
+            Err(v) => format!("{v:?}"),
+        }
+    }
+
+    #[test]
+    fn accepts_authentic_messages_once() {
+        let sender = authenticator("key", TOKEN, None);
+        let validator = registered(authenticator("key", TOKEN, None), &[]);
+
+        let sealed = sender.seal(&topic(), payload()).unwrap();
+        let message = message(sealed, None);
+        assert_eq!(outcome(&validator, &message), "Accept");
+        // replayed
+        assert_eq!(outcome(&validator, &message), "Ignore");
+    }
+
+    #[test]
+    fn maps_every_envelope_error() {
+        let validator = registered(authenticator("key", TOKEN, None), &[]);
+        let seal = |sender: &Authenticator, timestamp_millis| {
+            sender
+                .seal_at(&topic(), payload(), timestamp_millis)
+                .unwrap()
+        };
+        let now = now_millis();
+        let late = now - TIMESTAMP_WINDOW.as_millis() as u64 - 1000;
+        let expired = NaiveDate::from_ymd_opt(2023, 1, 1);
+
+        let cases = [
+            ("malformed", message(b"garbage".to_vec(), None), "Reject"),
+            (
+                "sender mismatch",
+                message(
+                    seal(&authenticator("key", TOKEN, None), now),
+                    Some(PeerId::random()),
+                ),
+                "Reject",
+            ),
+            (
+                "invalid mac",
+                message(
+                    seal(
+                        &authenticator("key", "another-token-which-is-long", None),
+                        now,
+                    ),
+                    None,
+                ),
+                "Reject",
+            ),
+            (
+                "unknown key",
+                message(seal(&authenticator("other", TOKEN, None), now), None),
+                "Ignore",
+            ),
+            (
+                "outside window",
+                message(seal(&authenticator("key", TOKEN, None), late), None),
+                "Ignore",
+            ),
+        ];
+        for (name, message, expected) in cases {
+            assert_eq!(outcome(&validator, &message), expected, "{name}");
+        }
+
+        let expired_validator = registered(authenticator("key", TOKEN, expired), &[]);
+        let sealed = seal(&authenticator("key", TOKEN, None), now);
+        assert_eq!(
+            outcome(&expired_validator, &message(sealed, None)),
+            "Ignore"
+        );
+    }
+
+    #[test]
+    fn ignores_unknown_topics_and_peers() {
+        let sender = authenticator("key", TOKEN, None);
+        let sealed = sender.seal(&topic(), payload()).unwrap();
+
+        let unregistered = MessageValidator::new(authenticator("key", TOKEN, None), &[]);
+        assert_eq!(
+            outcome(&unregistered, &message(sealed.clone(), None)),
+            "Ignore"
+        );
+
+        let restricted = registered(authenticator("key", TOKEN, None), &[PeerId::random()]);
+        assert_eq!(outcome(&restricted, &message(sealed, None)), "Ignore");
+    }
+
+    #[test]
+    fn ignores_undecodable_payloads_only_from_newer_versions() {
+        let sender = authenticator("key", TOKEN, None);
+        let validator = registered(authenticator("key", TOKEN, None), &[]);
+
+        let sealed = sender.seal(&topic(), b"garbage".to_vec()).unwrap();
+        assert_eq!(outcome(&validator, &message(sealed, None)), "Reject");
+
+        let sealed = sender.seal(&topic(), b"garbage".to_vec()).unwrap();
+        let future = with_version(&sealed, PROTOCOL_VERSION + 1);
+        assert_eq!(outcome(&validator, &message(future, None)), "Ignore");
     }
 }
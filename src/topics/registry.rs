@@ -0,0 +1,289 @@
+use std::{collections::HashMap, error::Error};
+
+use futures::future::BoxFuture;
+use kanal::AsyncSender;
+use libp2p::gossipsub::{self, TopicHash};
+use log::{error, warn};
+use tokio::time;
+
+use super::{
+    codec::{Encoder, WireMessage},
+    extract_topic_message, MessageValidator, Topic, ValidatedMessage,
+};
+
+type Handler = Box<dyn Fn(ValidatedMessage) -> BoxFuture<'static, ()> + Send + Sync>;
+
+/// Publishes messages on a single topic
+#[derive(Clone)]
+pub struct Publisher {
+    topic_hash: TopicHash,
+    sender: AsyncSender<(TopicHash, Vec<u8>)>,
+    encoder: Encoder,
+}
+
+impl Publisher {
+    /// Returns whether the message was handed to the swarm
+    pub async fn publish<M: WireMessage>(&self, message: &M) -> bool {
+        let payload = match self.encoder.encode(message) {
+            Ok(v) => v,
+            Err(err) => {
+                error!("Serialize error: {err:#?}");
+                return false;
+            }
+        };
+
+        if let Err(err) = self.sender.send((self.topic_hash.clone(), payload)).await {
+            error!("Failed to send {err:#?}");
+            return false;
+        }
+        true
+    }
+}
+
+/// Subscribes the topics, runs their broadcasts and dispatches incoming messages to them
+pub struct TopicRegistry {
+    sender: AsyncSender<(TopicHash, Vec<u8>)>,
+    validator: MessageValidator,
+    encoder: Encoder,
+    handlers: HashMap<TopicHash, Handler>,
+}
+
+impl TopicRegistry {
+    pub fn new(
+        sender: &AsyncSender<(TopicHash, Vec<u8>)>,
+        validator: MessageValidator,
+        encoder: Encoder,
+    ) -> Self {
+        Self {
+            sender: sender.clone(),
+            validator,
+            encoder,
+            handlers: HashMap::new(),
+        }
+    }
+
+    /// Publishes on the topic of `T`, which may be registered later
+    pub fn publisher<T: Topic>(&self) -> Publisher {
+        Publisher {
+            topic_hash: gossipsub::IdentTopic::new(T::NAME).hash(),
+            sender: self.sender.clone(),
+            encoder: self.encoder,
+        }
+    }
+
+    pub fn register<T: Topic>(
+        &mut self,
+        gossipsub: &mut gossipsub::Behaviour,
+        topic: &T,
+    ) -> Result<(), Box<dyn Error>> {
+        let ident_topic = gossipsub::IdentTopic::new(T::NAME);
+        gossipsub.subscribe(&ident_topic)?;
+        let topic_hash = ident_topic.hash();
+        self.validator.register::<T::Message>(&topic_hash);
+
+        if let Some(period) = topic.broadcast_interval() {
+            let topic = topic.clone();
+            let publisher = self.publisher::<T>();
+            tokio::spawn(async move {
+                let mut interval = time::interval(period);
+                loop {
+                    interval.tick().await;
+                    if let Some(message) = topic.produce().await {
+                        publisher.publish(&message).await;
+                    }
+                }
+            });
+        }
+
+        let topic = topic.clone();
+        self.handlers.insert(
+            topic_hash,
+            Box::new(move |message| {
+                let topic = topic.clone();
+                Box::pin(async move {
+                    if let Some(data) = extract_topic_message::<T::Message>(&message) {
+                        topic.handle(data).await;
+                    }
+                })
+            }),
+        );
+        Ok(())
+    }
+
+    /// Checks incoming messages against the schemas of the registered topics
+    pub fn validator(&self) -> MessageValidator {
+        self.validator.clone()
+    }
+
+    /// Hands the message to its topic in a new task. Returns whether the topic is registered.
+    pub fn dispatch(&self, message: ValidatedMessage) -> bool {
+        match self.handlers.get(&message.topic) {
+            Some(handler) => {
+                tokio::spawn(handler(message));
+                true
+            }
+            None => {
+                warn!("Got a message on unregistered topic {}", message.topic);
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use async_trait::async_trait;
+    use libp2p::{identity::Keypair, PeerId};
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::{
+        config::CodecConfig,
+        topics::{
+            codec,
+            host_shutdown::HostShutdownMessage,
+            testing::{authenticator, TOKEN},
+            ExtractedTopicMessage, PROTOCOL_VERSION,
+        },
+    };
+
+    /// Forwards the messages it handles and broadcasts a fixed one
+    #[derive(Clone)]
+    struct TestTopic {
+        handled: mpsc::UnboundedSender<String>,
+        broadcast: bool,
+    }
+
+    #[async_trait]
+    impl Topic for TestTopic {
+        type Message = HostShutdownMessage;
+
+        const NAME: &'static str = "dyn-wol-test";
+
+        fn broadcast_interval(&self) -> Option<Duration> {
+            self.broadcast.then_some(Duration::from_millis(10))
+        }
+
+        async fn produce(&self) -> Option<HostShutdownMessage> {
+            Some(message("broadcast"))
+        }
+
+        async fn handle(&self, data: ExtractedTopicMessage<HostShutdownMessage>) {
+            let _ = self.handled.send(data.message.target_peer_id);
+        }
+    }
+
+    fn message(target_peer_id: &str) -> HostShutdownMessage {
+        HostShutdownMessage {
+            target_peer_id: target_peer_id.to_string(),
+        }
+    }
+
+    fn registry(sender: &AsyncSender<(TopicHash, Vec<u8>)>) -> TopicRegistry {
+        TopicRegistry::new(
+            sender,
+            MessageValidator::new(authenticator("key", TOKEN, None), &[]),
+            Encoder::new(&CodecConfig::default()),
+        )
+    }
+
+    fn gossipsub() -> gossipsub::Behaviour {
+        gossipsub::Behaviour::new(
+            gossipsub::MessageAuthenticity::Signed(Keypair::generate_ed25519()),
+            gossipsub::Config::default(),
+        )
+        .unwrap()
+    }
+
+    fn validated(topic: TopicHash, payload: Vec<u8>) -> ValidatedMessage {
+        ValidatedMessage {
+            topic,
+            peer_id: PeerId::random(),
+            version: PROTOCOL_VERSION,
+            payload,
+        }
+    }
+
+    #[tokio::test]
+    async fn subscribes_registered_topics() {
+        let (sender, _receiver) = kanal::unbounded_async();
+        let mut registry = registry(&sender);
+        let mut gossipsub = gossipsub();
+        let (handled, _) = mpsc::unbounded_channel();
+
+        let topic = TestTopic {
+            handled,
+            broadcast: false,
+        };
+        registry.register(&mut gossipsub, &topic).unwrap();
+
+        let topic_hash = gossipsub::IdentTopic::new(TestTopic::NAME).hash();
+        assert!(gossipsub.topics().any(|v| v == &topic_hash));
+        assert!(registry
+            .validator()
+            .topic_hashes()
+            .any(|v| v == &topic_hash));
+    }
+
+    #[tokio::test]
+    async fn broadcasts_produced_messages() {
+        let (sender, receiver) = kanal::unbounded_async();
+        let mut registry = registry(&sender);
+        let (handled, _) = mpsc::unbounded_channel();
+
+        let topic = TestTopic {
+            handled,
+            broadcast: true,
+        };
+        registry.register(&mut gossipsub(), &topic).unwrap();
+
+        let (topic_hash, payload) = receiver.recv().await.unwrap();
+        assert_eq!(topic_hash.as_str(), TestTopic::NAME);
+        let decoded: HostShutdownMessage = codec::decode(PROTOCOL_VERSION, &payload).unwrap();
+        assert_eq!(decoded.target_peer_id, "broadcast");
+    }
+
+    #[tokio::test]
+    async fn dispatches_to_the_registered_topic() {
+        let (sender, _receiver) = kanal::unbounded_async();
+        let mut registry = registry(&sender);
+        let (handled, mut handled_receiver) = mpsc::unbounded_channel();
+
+        let topic = TestTopic {
+            handled,
+            broadcast: false,
+        };
+        registry.register(&mut gossipsub(), &topic).unwrap();
+
+        let payload = Encoder::new(&CodecConfig::default())
+            .encode(&message("dispatched"))
+            .unwrap();
+        let topic_hash = gossipsub::IdentTopic::new(TestTopic::NAME).hash();
+        assert!(registry.dispatch(validated(topic_hash, payload)));
+        assert_eq!(handled_receiver.recv().await.unwrap(), "dispatched");
+    }
+
+    #[tokio::test]
+    async fn drops_messages_on_unknown_topics() {
+        let (sender, _receiver) = kanal::unbounded_async();
+        let mut registry = registry(&sender);
+        let (handled, mut handled_receiver) = mpsc::unbounded_channel();
+
+        let topic = TestTopic {
+            handled,
+            broadcast: false,
+        };
+        registry.register(&mut gossipsub(), &topic).unwrap();
+
+        let payload = Encoder::new(&CodecConfig::default())
+            .encode(&message("lost"))
+            .unwrap();
+        let unknown = gossipsub::IdentTopic::new("dyn-wol-unknown").hash();
+        assert!(!registry.dispatch(validated(unknown, payload)));
+        drop(registry);
+        drop(topic);
+        assert_eq!(handled_receiver.recv().await, None);
+    }
+}
@@ -0,0 +1,28 @@
+//! Helpers shared by the tests of the topics
+
+use chrono::NaiveDate;
+use libp2p::PeerId;
+
+use super::envelope::Authenticator;
+use crate::config::{TokenKey, Tokens};
+
+pub(super) use super::envelope::now_millis;
+
+pub(super) const TOKEN: &str = "an-example-token-which-is-long-enough";
+
+pub(super) fn tokens(keys: &[(&str, &str, Option<NaiveDate>)]) -> Tokens {
+    Tokens(
+        keys.iter()
+            .map(|(id, secret, not_after)| TokenKey {
+                id: id.to_string(),
+                secret: secret.to_string(),
+                not_after: *not_after,
+            })
+            .collect(),
+    )
+}
+
+/// Authenticates a random peer with a single key
+pub(super) fn authenticator(id: &str, secret: &str, not_after: Option<NaiveDate>) -> Authenticator {
+    Authenticator::new(&tokens(&[(id, secret, not_after)]), PeerId::random())
+}
@@ -0,0 +1,417 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    config::{ConfiguredHost, SecureOnPassword, WolTarget},
+    events::{ClusterEvent, EventBus},
+    reload::LiveConfig,
+    send_activation_action::send_activation_action,
+};
+use async_trait::async_trait;
+use libp2p::PeerId;
+use log::{info, warn};
+use mac_address::MacAddress;
+use rand_core::{OsRng, RngCore};
+use sysinfo::{IpNetwork, Networks};
+use tokio::{sync::RwLock, time};
+
+use super::{
+    codec::{mac_from_bytes, WireMessage},
+    host_info::local_name,
+    registry::Publisher,
+    ExtractedTopicMessage, Topic,
+};
+
+/// How long a relay has to acknowledge a request before it is given up on
+const ACK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Lets hosts on other subnets be woken by a peer which shares a subnet with them.
+///
+/// A host which names a relay is only ever woken by that peer. Otherwise a host whose address is
+/// on one of our subnets is woken by us alone and any other address by the peers it is local to.
+/// Packets to the limited broadcast address never leave the segment they are sent on, so we send
+/// them ourselves and ask every other peer to do the same on its segments.
+#[derive(Clone)]
+pub struct WakeRelay {
+    publisher: Publisher,
+    local_peer_id: PeerId,
+    config: LiveConfig,
+    events: EventBus,
+    /// Requests we sent and still wait for an acknowledgement of
+    outstanding: Arc<RwLock<HashMap<u64, Outstanding>>>,
+}
+
+struct Outstanding {
+    mac_address: MacAddress,
+    name: String,
+    sent_at: Instant,
+    /// Whether we sent the packet on our segment as well, so no acknowledgement is fine
+    sent_locally: bool,
+}
+
+/// Who sends the magic packet for a host
+#[derive(Debug, PartialEq)]
+enum Route {
+    /// Only we do
+    Local,
+    /// Only other peers do
+    Relayed,
+    /// Every peer does on its own segment
+    Everywhere,
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub enum WakeRelayMessage {
+    Request {
+        request_id: u64,
+        mac_address: MacAddress,
+        address: IpAddr,
+        port: u16,
+        secure_on_password: Option<[u8; 6]>,
+        /// The host name or peer id of the peer which should send the packet
+        relay: Option<String>,
+    },
+    Ack {
+        request_id: u64,
+        name: String,
+    },
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct WakeRelayProto {
+    #[prost(oneof = "WakeRelayKind", tags = "1, 2")]
+    kind: Option<WakeRelayKind>,
+}
+
+#[derive(Clone, PartialEq, prost::Oneof)]
+pub enum WakeRelayKind {
+    #[prost(message, tag = "1")]
+    Request(WakeRelayRequestProto),
+    #[prost(message, tag = "2")]
+    Ack(WakeRelayAckProto),
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct WakeRelayRequestProto {
+    #[prost(uint64, tag = "1")]
+    request_id: u64,
+    #[prost(bytes = "vec", tag = "2")]
+    mac_address: Vec<u8>,
+    /// The address the packet is sent to, as text
+    #[prost(string, tag = "3")]
+    address: String,
+    #[prost(uint32, tag = "4")]
+    port: u32,
+    #[prost(bytes = "vec", optional, tag = "5")]
+    secure_on_password: Option<Vec<u8>>,
+    #[prost(string, optional, tag = "6")]
+    relay: Option<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct WakeRelayAckProto {
+    #[prost(uint64, tag = "1")]
+    request_id: u64,
+    #[prost(string, tag = "2")]
+    name: String,
+}
+
+impl WireMessage for WakeRelayMessage {
+    type Proto = WakeRelayProto;
+
+    fn to_proto(&self) -> WakeRelayProto {
+        let kind = match self {
+            WakeRelayMessage::Request {
+                request_id,
+                mac_address,
+                address,
+                port,
+                secure_on_password,
+                relay,
+            } => WakeRelayKind::Request(WakeRelayRequestProto {
+                request_id: *request_id,
+                mac_address: mac_address.bytes().to_vec(),
+                address: address.to_string(),
+                port: (*port).into(),
+                secure_on_password: secure_on_password.map(|v| v.to_vec()),
+                relay: relay.clone(),
+            }),
+            WakeRelayMessage::Ack { request_id, name } => WakeRelayKind::Ack(WakeRelayAckProto {
+                request_id: *request_id,
+                name: name.clone(),
+            }),
+        };
+        WakeRelayProto { kind: Some(kind) }
+    }
+
+    fn from_proto(proto: WakeRelayProto) -> Result<Self, String> {
+        match proto.kind.ok_or("The wake relay message is empty")? {
+            WakeRelayKind::Request(v) => Ok(WakeRelayMessage::Request {
+                request_id: v.request_id,
+                mac_address: mac_from_bytes(&v.mac_address)?,
+                address: v.address.parse().map_err(|_| "Invalid address")?,
+                port: v.port.try_into().map_err(|_| "Invalid port")?,
+                secure_on_password: v
+                    .secure_on_password
+                    .map(|v| v.try_into().map_err(|_| "A SecureOn password has 6 bytes"))
+                    .transpose()?,
+                relay: v.relay,
+            }),
+            WakeRelayKind::Ack(v) => Ok(WakeRelayMessage::Ack {
+                request_id: v.request_id,
+                name: v.name,
+            }),
+        }
+    }
+}
+
+impl WakeRelay {
+    pub fn new(
+        publisher: Publisher,
+        local_peer_id: PeerId,
+        config: &LiveConfig,
+        events: &EventBus,
+    ) -> Self {
+        WakeRelay {
+            publisher,
+            local_peer_id,
+            config: config.clone(),
+            events: events.clone(),
+            outstanding: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    /// Wakes the host directly if we are its relay, otherwise asks the other peers to do so.
+    /// Returns whether the packet or the request was sent, requests which no relay acknowledges
+    /// in time are logged.
+    pub async fn wake(&self, host: &ConfiguredHost) -> bool {
+        // gossipsub does not deliver our own requests back to us
+        let route = route(host.relay.as_deref(), host.wol.address, &self.local_peer_id);
+        let sent_locally = match route {
+            Route::Local => return send_activation_action(host).await,
+            Route::Relayed => false,
+            Route::Everywhere => send_activation_action(host).await,
+        };
+
+        let request_id = OsRng.next_u64();
+        let message = WakeRelayMessage::Request {
+            request_id,
+            mac_address: host.mac_address,
+            address: host.wol.address,
+            port: host.wol.port,
+            secure_on_password: host.wol.secure_on_password.as_ref().map(|v| v.0),
+            relay: host.relay.clone(),
+        };
+
+        info!("Asking peers to relay the wake of {}", host.name);
+        self.outstanding.write().await.insert(
+            request_id,
+            Outstanding {
+                mac_address: host.mac_address,
+                name: host.name.clone(),
+                sent_at: Instant::now(),
+                sent_locally,
+            },
+        );
+        if !self.publisher.publish(&message).await {
+            self.outstanding.write().await.remove(&request_id);
+            return sent_locally;
+        }
+
+        tokio::spawn({
+            let relay = self.clone();
+            async move {
+                time::sleep(ACK_TIMEOUT).await;
+                relay.expire_outstanding(Instant::now()).await;
+            }
+        });
+        true
+    }
+
+    /// Gives up on the requests which were not acknowledged within the timeout
+    async fn expire_outstanding(&self, now: Instant) {
+        self.outstanding.write().await.retain(|_, v| {
+            let expired = now.duration_since(v.sent_at) >= ACK_TIMEOUT;
+            if expired && !v.sent_locally {
+                warn!(
+                    "No peer acknowledged relaying the wake of {} within {ACK_TIMEOUT:?}",
+                    v.name
+                );
+            }
+            !expired
+        });
+    }
+}
+
+#[async_trait]
+impl Topic for WakeRelay {
+    type Message = WakeRelayMessage;
+
+    const NAME: &'static str = "dyn-wol-wake-relay";
+
+    async fn handle(&self, data: ExtractedTopicMessage<WakeRelayMessage>) {
+        match data.message {
+            WakeRelayMessage::Request {
+                request_id,
+                mac_address,
+                address,
+                port,
+                secure_on_password,
+                relay,
+            } => {
+                if route(relay.as_deref(), address, &self.local_peer_id) == Route::Relayed {
+                    return;
+                }
+
+                // our own entry for the host knows best how to reach it from here
+                let host = self
+                    .config
+                    .borrow()
+                    .hosts
+                    .iter()
+                    .find(|v| v.mac_address == mac_address)
+                    .cloned()
+                    .unwrap_or_else(|| ConfiguredHost {
+                        name: mac_address.to_string(),
+                        mac_address,
+                        wol: WolTarget {
+                            address,
+                            port,
+                            secure_on_password: secure_on_password.map(SecureOnPassword),
+                            ..Default::default()
+                        },
+                        ..Default::default()
+                    });
+
+                info!("Relaying wake of {} for peer {}", host.name, data.peer_id);
+                let publisher = self.publisher.clone();
+                tokio::spawn(async move {
+                    if !send_activation_action(&host).await {
+                        return;
+                    }
+
+                    let name = local_name().unwrap_or_default();
+                    publisher
+                        .publish(&WakeRelayMessage::Ack { request_id, name })
+                        .await;
+                });
+            }
+            WakeRelayMessage::Ack { request_id, name } => {
+                let mac_address = match self.outstanding.write().await.remove(&request_id) {
+                    Some(v) => v.mac_address,
+                    None => return,
+                };
+
+                self.events.emit(ClusterEvent::WakeRelayed {
+                    mac_address,
+                    peer_id: data.peer_id,
+                    name,
+                });
+            }
+        }
+    }
+}
+
+fn route(relay: Option<&str>, address: IpAddr, local_peer_id: &PeerId) -> Route {
+    match relay {
+        Some(relay)
+            if local_name().is_some_and(|v| v == relay) || relay == local_peer_id.to_string() =>
+        {
+            Route::Local
+        }
+        Some(_) => Route::Relayed,
+        None if is_limited_broadcast(address) => Route::Everywhere,
+        None if is_on_local_subnet(address) => Route::Local,
+        None => Route::Relayed,
+    }
+}
+
+/// Whether the packet is sent on every segment of the sender and no further
+fn is_limited_broadcast(address: IpAddr) -> bool {
+    address.is_unspecified() || address == IpAddr::V4(std::net::Ipv4Addr::BROADCAST)
+}
+
+/// Whether a magic packet sent to the address will reach its target without crossing a router
+pub fn is_on_local_subnet(address: IpAddr) -> bool {
+    Networks::new_with_refreshed_list()
+        .values()
+        .flat_map(|v| v.ip_networks())
+        .any(|network| network_contains(network, address))
+}
+
+fn network_contains(network: &IpNetwork, address: IpAddr) -> bool {
+    match (network.addr, address) {
+        (IpAddr::V4(network_address), IpAddr::V4(address)) => {
+            let mask = u32::MAX
+                .checked_shl(32 - network.prefix.min(32) as u32)
+                .unwrap_or(0);
+            u32::from(network_address) & mask == u32::from(address) & mask
+        }
+        (IpAddr::V6(network_address), IpAddr::V6(address)) => {
+            let mask = u128::MAX
+                .checked_shl(128 - network.prefix.min(128) as u32)
+                .unwrap_or(0);
+            u128::from(network_address) & mask == u128::from(address) & mask
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    /// Reserved for documentation, so hardly ever on one of our subnets
+    const REMOTE: IpAddr = IpAddr::V4(Ipv4Addr::new(203, 0, 113, 1));
+
+    #[test]
+    fn only_the_named_relay_sends() {
+        let local_peer_id = PeerId::random();
+        let us = local_peer_id.to_string();
+        assert_eq!(route(Some(&us), REMOTE, &local_peer_id), Route::Local);
+        if let Some(name) = local_name() {
+            assert_eq!(route(Some(&name), REMOTE, &local_peer_id), Route::Local);
+        }
+
+        let other = PeerId::random().to_string();
+        let broadcast = IpAddr::V4(Ipv4Addr::BROADCAST);
+        assert_eq!(
+            route(Some(&other), broadcast, &local_peer_id),
+            Route::Relayed
+        );
+    }
+
+    #[test]
+    fn relays_without_a_named_relay() {
+        let local_peer_id = PeerId::random();
+        if !is_on_local_subnet(REMOTE) {
+            assert_eq!(route(None, REMOTE, &local_peer_id), Route::Relayed);
+        }
+        // no peer knows which segment the host is on
+        let broadcast = IpAddr::V4(Ipv4Addr::BROADCAST);
+        assert_eq!(route(None, broadcast, &local_peer_id), Route::Everywhere);
+    }
+
+    #[test]
+    fn matches_addresses_by_prefix() {
+        let network = IpNetwork {
+            addr: IpAddr::V4(Ipv4Addr::new(10, 0, 1, 20)),
+            prefix: 24,
+        };
+        assert!(network_contains(
+            &network,
+            IpAddr::V4(Ipv4Addr::new(10, 0, 1, 255))
+        ));
+        assert!(!network_contains(
+            &network,
+            IpAddr::V4(Ipv4Addr::new(10, 0, 2, 1))
+        ));
+        assert!(!network_contains(&network, "::1".parse().unwrap()));
+    }
+}
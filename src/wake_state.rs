@@ -0,0 +1,365 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use log::info;
+use mac_address::MacAddress;
+
+use crate::{
+    config::WakeConfig,
+    events::{ClusterEvent, EventBus},
+};
+
+/// Remembers past wake decisions so a single load spike or a slowly booting host does not
+/// cause a burst of magic packets
+pub struct WakeState {
+    config: WakeConfig,
+    events: EventBus,
+    breach_since: Option<Instant>,
+    pending: HashMap<MacAddress, PendingWake>,
+    /// Hosts which did not come up after all retries and until when they are skipped
+    quarantined: HashMap<MacAddress, Instant>,
+    activations: VecDeque<Instant>,
+    blocked: bool,
+}
+
+/// A host which has been woken but did not show up yet
+struct PendingWake {
+    sent_at: Instant,
+    /// Starts at 1 for the first magic packet
+    attempt: u32,
+    timed_out: bool,
+}
+
+impl PendingWake {
+    fn new(now: Instant) -> Self {
+        Self {
+            sent_at: now,
+            attempt: 1,
+            timed_out: false,
+        }
+    }
+}
+
+impl WakeState {
+    pub fn new(config: WakeConfig, events: EventBus) -> Self {
+        Self {
+            config,
+            events,
+            breach_since: None,
+            pending: HashMap::new(),
+            quarantined: HashMap::new(),
+            activations: VecDeque::new(),
+            blocked: false,
+        }
+    }
+
+    /// Applies a reloaded config, pending wakes and quarantines are kept
+    pub fn set_config(&mut self, config: WakeConfig) {
+        self.config = config;
+    }
+
+    /// Feeds the latest decision into the state machine and returns how many hosts may be
+    /// woken right now
+    pub fn allowed_wakes(&mut self, breached: bool, now: Instant) -> usize {
+        if !breached {
+            if self.breach_since.take().is_some() {
+                info!("Occupation level is back below the threshold");
+            }
+            self.blocked = false;
+            return 0;
+        }
+
+        let since = *self.breach_since.get_or_insert_with(|| {
+            info!(
+                "Occupation level breached the threshold, waiting {}s before waking a host",
+                self.config.breach_hold_seconds
+            );
+            now
+        });
+        if now.duration_since(since) < Duration::from_secs(self.config.breach_hold_seconds) {
+            return 0;
+        }
+
+        let window = Duration::from_secs(self.config.activation_window_seconds);
+        while self
+            .activations
+            .front()
+            .is_some_and(|v| now.duration_since(*v) >= window)
+        {
+            self.activations.pop_front();
+        }
+
+        if let Some(last) = self.activations.back() {
+            if now.duration_since(*last) < Duration::from_secs(self.config.cooldown_seconds) {
+                self.set_blocked("the activation cooldown is active");
+                return 0;
+            }
+        }
+
+        if self.activations.len() >= self.config.max_activations {
+            self.set_blocked("the activation limit for the current window is reached");
+            return 0;
+        }
+
+        self.blocked = false;
+        self.config.max_activations - self.activations.len()
+    }
+
+    /// Clears pending hosts which came up, quarantines the ones which did not come up after
+    /// all retries and returns the ones which should be woken again
+    pub fn update_pending(
+        &mut self,
+        running_mac_addresses: &[MacAddress],
+        now: Instant,
+    ) -> Vec<MacAddress> {
+        self.quarantined.retain(|mac_address, until| {
+            if now < *until {
+                return true;
+            }
+            info!("Host {mac_address} is no longer quarantined");
+            false
+        });
+
+        let boot_timeout = Duration::from_secs(self.config.boot_timeout_seconds);
+        let mut retries = Vec::new();
+        // hosts which are no longer pending and whether they turned out unresponsive
+        let mut finished = Vec::new();
+        for (mac_address, wake) in self.pending.iter_mut() {
+            if running_mac_addresses.contains(mac_address) {
+                self.events.emit(ClusterEvent::HostBooted {
+                    mac_address: *mac_address,
+                    attempt: wake.attempt,
+                });
+                finished.push((*mac_address, false));
+                continue;
+            }
+
+            let waited = now.duration_since(wake.sent_at);
+            if waited < boot_timeout {
+                continue;
+            }
+            if !wake.timed_out {
+                wake.timed_out = true;
+                self.events.emit(ClusterEvent::WakeFailed {
+                    mac_address: *mac_address,
+                    attempt: wake.attempt,
+                });
+            }
+
+            if wake.attempt > self.config.max_retries {
+                finished.push((*mac_address, true));
+            } else if waited >= boot_timeout + retry_backoff(&self.config, wake.attempt) {
+                retries.push(*mac_address);
+            }
+        }
+
+        for (mac_address, unresponsive) in finished {
+            let Some(wake) = self.pending.remove(&mac_address) else {
+                continue;
+            };
+            if !unresponsive {
+                continue;
+            }
+            self.quarantined.insert(
+                mac_address,
+                now + Duration::from_secs(self.config.quarantine_seconds),
+            );
+            self.events.emit(ClusterEvent::HostUnresponsive {
+                mac_address,
+                attempts: wake.attempt,
+                quarantine_seconds: self.config.quarantine_seconds,
+            });
+        }
+        retries
+    }
+
+    /// Remembers that the magic packet for a pending host was sent again
+    pub fn record_retry(&mut self, mac_address: MacAddress, now: Instant) {
+        if let Some(wake) = self.pending.get_mut(&mac_address) {
+            wake.sent_at = now;
+            wake.attempt += 1;
+            wake.timed_out = false;
+        }
+    }
+
+    /// The attempt which a retry of the host would be, if the host is still pending
+    pub fn next_attempt(&self, mac_address: &MacAddress) -> Option<u32> {
+        self.pending.get(mac_address).map(|v| v.attempt + 1)
+    }
+
+    pub fn pending_mac_addresses(&self) -> Vec<MacAddress> {
+        self.pending.keys().copied().collect()
+    }
+
+    pub fn quarantined_mac_addresses(&self) -> Vec<MacAddress> {
+        self.quarantined.keys().copied().collect()
+    }
+
+    pub fn record_activation(&mut self, mac_address: MacAddress, now: Instant) {
+        self.pending.insert(mac_address, PendingWake::new(now));
+        self.activations.push_back(now);
+        // a new breach has to be sustained before the next host is woken
+        self.breach_since = None;
+    }
+
+    /// Tracks a manually woken host until it boots, without counting it as an activation. This
+    /// also lifts a quarantine, since someone explicitly asked for the host.
+    pub fn record_manual_activation(&mut self, mac_address: MacAddress, now: Instant) {
+        self.quarantined.remove(&mac_address);
+        self.pending.insert(mac_address, PendingWake::new(now));
+    }
+
+    fn set_blocked(&mut self, reason: &str) {
+        if !self.blocked {
+            info!("Not waking a host since {reason}");
+            self.blocked = true;
+        }
+    }
+}
+
+/// The first retry waits the configured backoff, every further one twice as long
+fn retry_backoff(config: &WakeConfig, attempt: u32) -> Duration {
+    let factor = 1u64
+        .checked_shl(attempt.saturating_sub(1))
+        .unwrap_or(u64::MAX);
+    Duration::from_secs(config.retry_backoff_seconds.saturating_mul(factor))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAC: [u8; 6] = [0x01, 0x23, 0x45, 0x67, 0x89, 0xab];
+
+    fn state() -> WakeState {
+        let config = WakeConfig {
+            boot_timeout_seconds: 10,
+            max_retries: 2,
+            retry_backoff_seconds: 5,
+            quarantine_seconds: 60,
+            ..Default::default()
+        };
+        WakeState::new(config, EventBus::new())
+    }
+
+    fn limited(
+        breach_hold_seconds: u64,
+        cooldown_seconds: u64,
+        max_activations: usize,
+    ) -> WakeState {
+        let config = WakeConfig {
+            breach_hold_seconds,
+            cooldown_seconds,
+            max_activations,
+            activation_window_seconds: 100,
+            ..Default::default()
+        };
+        WakeState::new(config, EventBus::new())
+    }
+
+    #[test]
+    fn waits_for_a_sustained_breach() {
+        let mut state = limited(30, 0, 2);
+        let start = Instant::now();
+        let at = |seconds| start + Duration::from_secs(seconds);
+
+        assert_eq!(state.allowed_wakes(true, at(0)), 0);
+        assert_eq!(state.allowed_wakes(true, at(29)), 0);
+        assert_eq!(state.allowed_wakes(true, at(30)), 2);
+
+        // dropping below the threshold starts the hold over
+        assert_eq!(state.allowed_wakes(false, at(31)), 0);
+        assert_eq!(state.allowed_wakes(true, at(32)), 0);
+        assert_eq!(state.allowed_wakes(true, at(61)), 0);
+        assert_eq!(state.allowed_wakes(true, at(62)), 2);
+    }
+
+    #[test]
+    fn waits_for_the_cooldown_after_a_wake() {
+        let mut state = limited(0, 20, 3);
+        let start = Instant::now();
+        let at = |seconds| start + Duration::from_secs(seconds);
+
+        assert_eq!(state.allowed_wakes(true, at(0)), 3);
+        state.record_activation(MacAddress::new(MAC), at(0));
+        assert_eq!(state.allowed_wakes(true, at(19)), 0);
+        assert_eq!(state.allowed_wakes(true, at(20)), 2);
+    }
+
+    #[test]
+    fn limits_the_wakes_per_window() {
+        let mut state = limited(0, 0, 2);
+        let start = Instant::now();
+        let at = |seconds| start + Duration::from_secs(seconds);
+
+        state.record_activation(MacAddress::new(MAC), at(0));
+        state.record_activation(MacAddress::new([2; 6]), at(10));
+        assert_eq!(state.allowed_wakes(true, at(20)), 0);
+        assert_eq!(state.allowed_wakes(true, at(99)), 0);
+        // the first wake leaves the window
+        assert_eq!(state.allowed_wakes(true, at(100)), 1);
+        assert_eq!(state.allowed_wakes(true, at(110)), 2);
+    }
+
+    #[test]
+    fn retries_with_backoff_and_quarantines() {
+        let mut state = state();
+        let start = Instant::now();
+        let at = |seconds| start + Duration::from_secs(seconds);
+        state.record_activation(MacAddress::new(MAC), start);
+
+        // timed out, but the first backoff is not over yet
+        assert!(state.update_pending(&[], at(12)).is_empty());
+        assert_eq!(
+            state.update_pending(&[], at(15)),
+            vec![MacAddress::new(MAC)]
+        );
+        state.record_retry(MacAddress::new(MAC), at(15));
+
+        // the second backoff is twice as long
+        assert!(state.update_pending(&[], at(34)).is_empty());
+        assert_eq!(
+            state.update_pending(&[], at(35)),
+            vec![MacAddress::new(MAC)]
+        );
+        state.record_retry(MacAddress::new(MAC), at(35));
+
+        assert!(state.update_pending(&[], at(45)).is_empty());
+        assert!(state.pending_mac_addresses().is_empty());
+        assert_eq!(
+            state.quarantined_mac_addresses(),
+            vec![MacAddress::new(MAC)]
+        );
+
+        state.update_pending(&[], at(105));
+        assert!(state.quarantined_mac_addresses().is_empty());
+    }
+
+    #[test]
+    fn booted_host_is_no_longer_pending() {
+        let mut state = state();
+        let start = Instant::now();
+        state.record_activation(MacAddress::new(MAC), start);
+
+        let retries =
+            state.update_pending(&[MacAddress::new(MAC)], start + Duration::from_secs(20));
+        assert!(retries.is_empty());
+        assert!(state.pending_mac_addresses().is_empty());
+        assert!(state.quarantined_mac_addresses().is_empty());
+    }
+
+    #[test]
+    fn manual_wake_lifts_quarantine() {
+        let mut state = state();
+        let start = Instant::now();
+        state
+            .quarantined
+            .insert(MacAddress::new(MAC), start + Duration::from_secs(60));
+
+        state.record_manual_activation(MacAddress::new(MAC), start);
+        assert!(state.quarantined_mac_addresses().is_empty());
+        assert_eq!(state.pending_mac_addresses(), vec![MacAddress::new(MAC)]);
+    }
+}